@@ -0,0 +1,165 @@
+/*!
+A mock Ref-Finance-style AMM which swaps tokens at fixed prices set by its owner.
+The same prices are served through a price oracle interface, so that rebalancing a token set
+can be simulated end to end.
+*/
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, setup_alloc, AccountId, Balance, Gas, PanicOnDefault,
+    PromiseOrValue, PromiseResult,
+};
+
+setup_alloc!();
+
+const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+const GAS_FOR_ON_PAYOUT: Gas = 5_000_000_000_000;
+const NO_DEPOSIT: Balance = 0;
+const ONE_YOCTO: Balance = 1;
+const FEE_DENOMINATOR: u32 = 10_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Price {
+    pub multiplier: U128,
+    pub decimals: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetOptionalPrice {
+    pub asset_id: AccountId,
+    pub price: Option<Price>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceData {
+    pub timestamp: U64,
+    pub recency_duration_sec: u32,
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: Option<U128>,
+    pub min_amount_out: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapMsg {
+    pub actions: Vec<SwapAction>,
+}
+
+#[ext_contract(ext_fungible_token)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[ext_contract(ext_self)]
+pub trait AmmCallbacks {
+    fn on_payout(&mut self, amount_in: U128) -> U128;
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Amm {
+    owner_id: AccountId,
+    prices: LookupMap<AccountId, Price>,
+    /// Swaps pay out this many basis points less than the prices imply
+    fee_bps: u32,
+}
+
+#[near_bindgen]
+impl Amm {
+    #[init]
+    pub fn new(owner_id: ValidAccountId, fee_bps: u32) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self { owner_id: owner_id.into(), prices: LookupMap::new(b"p".to_vec()), fee_bps }
+    }
+
+    pub fn set_price(&mut self, token_id: ValidAccountId, price: Price) {
+        assert_eq!(env::predecessor_account_id(), self.owner_id, "Only the owner can set prices");
+        self.prices.insert(token_id.as_ref(), &price);
+    }
+
+    /// Price oracle interface
+    pub fn get_price_data(&self, asset_ids: Option<Vec<AccountId>>) -> PriceData {
+        PriceData {
+            timestamp: env::block_timestamp().into(),
+            recency_duration_sec: 90,
+            prices: asset_ids
+                .unwrap_or_default()
+                .into_iter()
+                .map(|asset_id| AssetOptionalPrice { price: self.prices.get(&asset_id), asset_id })
+                .collect(),
+        }
+    }
+
+    fn get_amount_out(&self, token_in: &AccountId, token_out: &AccountId, amount_in: u128) -> u128 {
+        let price_in = self.prices.get(token_in).expect("No price for token_in");
+        let price_out = self.prices.get(token_out).expect("No price for token_out");
+        let amount_out = amount_in * price_in.multiplier.0 * 10u128.pow(price_out.decimals as u32)
+            / (price_out.multiplier.0 * 10u128.pow(price_in.decimals as u32));
+        amount_out * (FEE_DENOMINATOR - self.fee_bps) as u128 / FEE_DENOMINATOR as u128
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Amm {
+    /// Executes the single swap action in `msg` and sends the output back to the sender. The
+    /// transfer resolves once the output has been paid, refunding the input if that failed.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_in = env::predecessor_account_id();
+        let swap: SwapMsg = serde_json::from_str(&msg).expect("Failed to parse the swap message");
+        assert_eq!(swap.actions.len(), 1, "Only single swaps are supported");
+        let action = &swap.actions[0];
+        assert_eq!(action.token_in, token_in, "The token sent is not the swap's token_in");
+
+        let amount_out = self.get_amount_out(&token_in, &action.token_out, amount.0);
+        assert!(amount_out >= action.min_amount_out.0, "E68: slippage error");
+        log!("Swapped {} {} for {} {}", amount.0, token_in, amount_out, action.token_out);
+
+        ext_fungible_token::ft_transfer(
+            sender_id.into(),
+            amount_out.into(),
+            None,
+            &action.token_out,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_payout(
+            amount,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_PAYOUT,
+        ))
+        .into()
+    }
+}
+
+#[near_bindgen]
+impl Amm {
+    /// return the unused part of the swap's input
+    #[private]
+    pub fn on_payout(&mut self, amount_in: U128) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => U128(0),
+            _ => amount_in,
+        }
+    }
+}
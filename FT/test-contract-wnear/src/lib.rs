@@ -0,0 +1,81 @@
+/*!
+A mock of the wNEAR contract (`wrap.near`): a fungible token minted by depositing NEAR with
+`near_deposit` and burned for NEAR with `near_withdraw`.
+*/
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LazyOption;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, Promise,
+    PromiseOrValue,
+};
+
+near_sdk::setup_alloc!();
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    token: FungibleToken,
+    metadata: LazyOption<FungibleTokenMetadata>,
+}
+
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Wrapped NEAR fungible token".to_string(),
+            symbol: "wNEAR".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        };
+        Self {
+            token: FungibleToken::new(b"a".to_vec()),
+            metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
+        }
+    }
+
+    /// Mint the attached NEAR as wNEAR for the caller, who has to be registered
+    #[payable]
+    pub fn near_deposit(&mut self) {
+        let amount = env::attached_deposit();
+        assert!(amount > 0, "Requires positive attached deposit");
+        self.token.internal_deposit(&env::predecessor_account_id(), amount);
+    }
+
+    /// Burn `amount` wNEAR of the caller and send it the NEAR
+    #[payable]
+    pub fn near_withdraw(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.token.internal_withdraw(&account_id, amount.0);
+        // Sends back the 1 yoctoNEAR as well
+        Promise::new(account_id).transfer(amount.0 + 1)
+    }
+
+    fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
+        log!("Closed @{} with {}", account_id, balance);
+    }
+
+    fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
+        log!("Account @{} burned {}", account_id, amount);
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(Contract, token, on_tokens_burned);
+near_contract_standards::impl_fungible_token_storage!(Contract, token, on_account_closed);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        self.metadata.get().unwrap()
+    }
+}
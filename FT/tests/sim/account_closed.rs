@@ -0,0 +1,106 @@
+use fungible_token::ContractContract as FtContract;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::Value;
+use near_sdk_sim::{call, view, ContractAccount, UserAccount};
+use token_set_fungible_token::{
+    AccountClosedPolicy, ContractContract as TokenSetContract, TokenWithRatio,
+};
+
+use crate::utils::{deposit_and_wrap, init_with_policy as init};
+
+fn internal_balances(
+    token_set: &ContractAccount<TokenSetContract>,
+    fts: &Vec<ContractAccount<FtContract>>,
+    user: &UserAccount,
+) -> Vec<u128> {
+    fts.iter()
+        .map(|ft| {
+            let balance: U128 = view!(
+                token_set.internal_balance_of(user.valid_account_id(), ft.valid_account_id())
+            )
+            .unwrap_json();
+            balance.0
+        })
+        .collect()
+}
+
+#[test]
+fn simulate_close_account_to_platform() {
+    let (root, _, token_set, _, fts, alice) =
+        init(vec![1, 2], None, None, 1_000, Some(AccountClosedPolicy::Platform));
+    deposit_and_wrap(&root, &alice, &token_set, &fts);
+
+    let outcome = call!(alice, token_set.storage_unregister(Some(true)), deposit = 1);
+    outcome.assert_success();
+    assert_eq!(outcome.logs()[0], format!("Closed @{} with {}", alice.account_id(), 100));
+
+    let total_supply: U128 = view!(token_set.ft_total_supply()).unwrap_json();
+    assert_eq!(total_supply.0, 0);
+    assert_eq!(internal_balances(&token_set, &fts, &root), vec![100, 200]);
+}
+
+#[test]
+fn simulate_close_account_to_underlying() {
+    let (root, _, token_set, _, fts, alice) =
+        init(vec![1, 2], None, None, 1_000, Some(AccountClosedPolicy::Underlying));
+    deposit_and_wrap(&root, &alice, &token_set, &fts);
+
+    call!(alice, token_set.storage_unregister(Some(true)), deposit = 1).assert_success();
+
+    let total_supply: U128 = view!(token_set.ft_total_supply()).unwrap_json();
+    assert_eq!(total_supply.0, 0);
+    assert_eq!(internal_balances(&token_set, &fts, &alice), vec![0, 0]);
+
+    // Claiming registers alice again and credits her the underlying tokens
+    let storage_deposit = near_sdk::env::storage_byte_cost() * 1000;
+    call!(alice, token_set.claim_escrow(), deposit = storage_deposit).assert_success();
+    let balance: U128 = view!(token_set.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 0);
+    assert_eq!(internal_balances(&token_set, &fts, &alice), vec![100, 200]);
+    assert_eq!(internal_balances(&token_set, &fts, &root), vec![0, 0]);
+}
+
+#[test]
+fn simulate_close_account_to_escrow() {
+    let (root, _, token_set, _, fts, alice) =
+        init(vec![1, 2], None, None, 1_000, Some(AccountClosedPolicy::Escrow));
+    deposit_and_wrap(&root, &alice, &token_set, &fts);
+
+    call!(alice, token_set.storage_unregister(Some(true)), deposit = 1).assert_success();
+    let escrowed = |user: &UserAccount| -> u128 {
+        let escrowed: U128 =
+            view!(token_set.escrowed_balance_of(user.valid_account_id())).unwrap_json();
+        escrowed.0
+    };
+    assert_eq!(escrowed(&alice), 100);
+    let storage: Value =
+        view!(token_set.storage_balance_of(alice.valid_account_id())).unwrap_json();
+    assert!(storage.is_null());
+
+    // Claiming registers alice again
+    let storage_deposit = near_sdk::env::storage_byte_cost() * 1000;
+    call!(alice, token_set.claim_escrow(), deposit = storage_deposit).assert_success();
+    let balance: U128 = view!(token_set.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 100);
+    assert_eq!(escrowed(&alice), 0);
+    let total_supply: U128 = view!(token_set.ft_total_supply()).unwrap_json();
+    assert_eq!(total_supply.0, 100);
+}
+
+#[test]
+fn simulate_close_account_donate_to_holders() {
+    let (root, owner_bob, token_set, _, fts, alice) =
+        init(vec![1, 2], None, None, 1_000, Some(AccountClosedPolicy::DonateToHolders));
+    deposit_and_wrap(&root, &alice, &token_set, &fts);
+    deposit_and_wrap(&root, &owner_bob, &token_set, &fts);
+
+    call!(alice, token_set.storage_unregister(Some(true)), deposit = 1).assert_success();
+
+    // Bob's 100 set tokens are now backed by all 200 ft-0 and 400 ft-1
+    let total_supply: U128 = view!(token_set.ft_total_supply()).unwrap_json();
+    assert_eq!(total_supply.0, 100);
+    let ratios: Vec<TokenWithRatio> = view!(token_set.get_set_ratios()).unwrap_json();
+    assert_eq!(ratios.iter().map(|r| r.ratio).collect::<Vec<u32>>(), vec![1, 2]);
+    call!(owner_bob, token_set.unwrap(None, None), deposit = 1).assert_success();
+    assert_eq!(internal_balances(&token_set, &fts, &owner_bob), vec![200, 400]);
+}
@@ -0,0 +1,77 @@
+use std::convert::TryFrom;
+
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk_sim::{call, deploy, view, ContractAccount, UserAccount};
+use token_set_fungible_token::{
+    ComponentAllowlist, ComponentsStatus, ContractContract as TokenSetContract, TokenWithRatioValid,
+};
+
+use crate::utils::{init_with_macros as init, TOKEN_SET_WASM_BYTES};
+
+fn deploy_set(
+    root: &UserAccount,
+    contract_id: &str,
+    token_ids: &[String],
+    component_allowlist: Option<ComponentAllowlist>,
+) -> ContractAccount<TokenSetContract> {
+    let ratios: Vec<TokenWithRatioValid> = token_ids
+        .iter()
+        .map(|token_id| TokenWithRatioValid {
+            token_id: ValidAccountId::try_from(token_id.clone()).unwrap(),
+            ratio: 1,
+            is_set: false,
+        })
+        .collect();
+    deploy!(
+        contract: TokenSetContract,
+        contract_id: contract_id,
+        bytes: &TOKEN_SET_WASM_BYTES,
+        signer_account: root,
+        init_method: new_default_meta(
+            root.valid_account_id(),
+            contract_id.to_string(),
+            contract_id.to_uppercase(),
+            None,
+            ratios,
+            U128::from(0),
+            root.valid_account_id(),
+            U128::from(0),
+            None,
+            component_allowlist
+        )
+    )
+}
+
+#[test]
+fn simulate_component_verification() {
+    let (root, _, token_set, _, fts, alice) = init(vec![1], None, None, 1_000);
+    let ft_id = fts[0].account_id();
+    let status: ComponentsStatus = view!(token_set.get_components_status()).unwrap_json();
+    assert_eq!(status, ComponentsStatus::Verified);
+
+    // alice is not a fungible token, so the set cannot be used until the platform approves it
+    let unvetted = deploy_set(&root, "unvetted-set", &[ft_id.clone(), alice.account_id()], None);
+    let status: ComponentsStatus = view!(unvetted.get_components_status()).unwrap_json();
+    assert_eq!(status, ComponentsStatus::Rejected);
+    let res = call!(alice, unvetted.wrap(None, None), deposit = near_sdk_sim::to_yocto("1"));
+    assert!(!res.is_ok());
+    let res = call!(alice, unvetted.approve_components(), deposit = 1);
+    assert!(!res.is_ok());
+    call!(root, unvetted.approve_components(), deposit = 1).assert_success();
+    let status: ComponentsStatus = view!(unvetted.get_components_status()).unwrap_json();
+    assert_eq!(status, ComponentsStatus::Verified);
+
+    // With a local allowlist, the components pass once the platform added them
+    let local = deploy_set(&root, "local-set", &[ft_id.clone()], Some(ComponentAllowlist::Local));
+    let status: ComponentsStatus = view!(local.get_components_status()).unwrap_json();
+    assert_eq!(status, ComponentsStatus::Rejected);
+    call!(
+        root,
+        local.add_allowed_tokens(vec![ValidAccountId::try_from(ft_id).unwrap()]),
+        deposit = 1
+    )
+    .assert_success();
+    call!(alice, local.verify_components()).assert_success();
+    let status: ComponentsStatus = view!(local.get_components_status()).unwrap_json();
+    assert_eq!(status, ComponentsStatus::Verified);
+}
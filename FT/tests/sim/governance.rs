@@ -0,0 +1,51 @@
+use near_sdk::json_types::{U128, U64};
+use near_sdk_sim::{call, to_yocto, view};
+use token_set_fungible_token::{GovernanceConfig, ProposalKind, ProposalStatus, ProposalView};
+
+use crate::utils::{deposit_and_wrap, init_with_macros as init};
+
+#[test]
+fn simulate_fee_proposal() {
+    let (root, owner_bob, token_set, _, fts, alice) = init(vec![1, 2], None, None, 1_000);
+    deposit_and_wrap(&root, &alice, &token_set, &fts);
+    let config = GovernanceConfig { quorum_bps: 5_000, voting_period_blocks: U64(10) };
+    call!(owner_bob, token_set.set_governance_config(config), deposit = 1).assert_success();
+    root.borrow_runtime_mut().produce_blocks(1).unwrap();
+
+    // Take 10% of every wrap for the platform
+    let kind = ProposalKind::Fees { owner_fee: U128(0), platform_fee: U128(100_000_000_000_000) };
+    let res = call!(
+        owner_bob,
+        token_set.propose(kind, "No tokens to propose with".to_string()),
+        deposit = to_yocto("0.1")
+    );
+    assert!(!res.is_ok());
+    let kind = ProposalKind::Fees { owner_fee: U128(0), platform_fee: U128(100_000_000_000_000) };
+    let id: u64 = call!(
+        alice,
+        token_set.propose(kind, "Pay the platform".to_string()),
+        deposit = to_yocto("0.1")
+    )
+    .unwrap_json();
+    let weight: U128 =
+        call!(alice, token_set.vote(id, true), deposit = to_yocto("0.01")).unwrap_json();
+    assert_eq!(weight.0, 100);
+    let res = call!(alice, token_set.vote(id, false), deposit = to_yocto("0.01"));
+    assert!(!res.is_ok());
+
+    // Votes only close once the voting period is over
+    let res = call!(root, token_set.execute_proposal(id));
+    assert!(!res.is_ok());
+    root.borrow_runtime_mut().produce_blocks(20).unwrap();
+    let proposal: ProposalView = view!(token_set.get_proposal(id)).unwrap_json();
+    assert_eq!(proposal.status, ProposalStatus::Passed);
+    call!(root, token_set.execute_proposal(id)).assert_success();
+    let proposal: ProposalView = view!(token_set.get_proposal(id)).unwrap_json();
+    assert_eq!(proposal.status, ProposalStatus::Executed);
+
+    // The platform gets 10 of the next 100 set tokens
+    deposit_and_wrap(&root, &alice, &token_set, &fts);
+    let platform_balance: U128 =
+        view!(token_set.ft_balance_of(root.valid_account_id())).unwrap_json();
+    assert_eq!(platform_balance.0, 10);
+}
@@ -1,3 +1,12 @@
+mod account_closed;
+mod allowlist;
+mod governance;
+mod native;
+mod nested;
 mod no_macros;
+mod rebalance;
+mod reserves;
+mod skim;
+mod upgrade;
 mod utils;
 mod with_macros;
@@ -0,0 +1,69 @@
+use std::convert::TryFrom;
+
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk_sim::{call, view};
+use token_set_fungible_token::TokenWithRatioValid;
+
+use crate::utils::{init_token_set, init_wnear, init_with_macros as init, storage_deposit};
+
+const NEAR_SET_ID: &str = "near-set";
+
+#[test]
+fn simulate_wrap_native_near() {
+    let (root, owner_bob, _, _, fts, alice) = init(vec![1], None, None, 1_000);
+    let wnear = init_wnear(&root);
+
+    // Each set token holds 1 ft-0 and 1000 yoctoNEAR
+    let set = init_token_set(
+        &root,
+        NEAR_SET_ID,
+        &owner_bob,
+        vec![
+            TokenWithRatioValid {
+                token_id: ValidAccountId::try_from(fts[0].account_id()).unwrap(),
+                ratio: 1,
+                is_set: false,
+            },
+            TokenWithRatioValid { token_id: wnear.valid_account_id(), ratio: 1_000, is_set: false },
+        ],
+    );
+    storage_deposit(&fts[0].account_id(), &set.user_account);
+    storage_deposit(&wnear.account_id(), &set.user_account);
+    storage_deposit(NEAR_SET_ID, &alice);
+    call!(owner_bob, set.set_wnear_id(Some(wnear.valid_account_id())), deposit = 1)
+        .assert_success();
+
+    call!(root, fts[0].ft_transfer(alice.valid_account_id(), U128(100), None), deposit = 1)
+        .assert_success();
+    call!(
+        alice,
+        fts[0].ft_transfer_call(
+            set.valid_account_id(),
+            U128(100),
+            None,
+            format!("{{\"sender_id\":\"{}\"}}", alice.account_id())
+        ),
+        deposit = 1
+    )
+    .assert_success();
+
+    // Only the NEAR backing the 100 set tokens the ft-0 allows is deposited as wNEAR, the rest
+    // is refunded
+    call!(alice, set.wrap(None, None), deposit = 150_001).assert_success();
+    let balance: U128 = view!(set.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(balance.0, 100);
+    let held: U128 = view!(wnear.ft_balance_of(set.valid_account_id())).unwrap_json();
+    assert_eq!(held.0, 100_000);
+
+    call!(alice, set.unwrap(Some(40), Some(true)), deposit = 1).assert_success();
+    let held: U128 = view!(wnear.ft_balance_of(set.valid_account_id())).unwrap_json();
+    assert_eq!(held.0, 60_000);
+    let internal_wnear: U128 =
+        view!(set.internal_balance_of(alice.valid_account_id(), wnear.valid_account_id()))
+            .unwrap_json();
+    assert_eq!(internal_wnear.0, 0);
+    let internal_ft: U128 =
+        view!(set.internal_balance_of(alice.valid_account_id(), fts[0].valid_account_id()))
+            .unwrap_json();
+    assert_eq!(internal_ft.0, 40);
+}
@@ -0,0 +1,97 @@
+use std::convert::TryFrom;
+
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk_sim::{call, view};
+use token_set_fungible_token::{TokenAmount, TokenWithRatioValid};
+
+use crate::utils::{init_token_set, init_with_macros as init, register_user, storage_deposit};
+
+const OUTER_SET_ID: &str = "outer-set";
+
+#[test]
+fn simulate_nested_set() {
+    let initial_balance = 1_000;
+    let (root, owner_bob, inner, _, fts, alice) = init(vec![1, 2], None, None, initial_balance);
+    let ft_ids: Vec<String> = fts.iter().map(|ft| ft.account_id()).collect();
+
+    // The outer set holds 2 inner set tokens and 1 ft-1 per outer set token
+    let outer = init_token_set(
+        &root,
+        OUTER_SET_ID,
+        &owner_bob,
+        vec![
+            TokenWithRatioValid { token_id: inner.valid_account_id(), ratio: 2, is_set: true },
+            TokenWithRatioValid {
+                token_id: ValidAccountId::try_from(ft_ids[1].clone()).unwrap(),
+                ratio: 1,
+                is_set: false,
+            },
+        ],
+    );
+    register_user(&ft_ids, &outer.user_account);
+    storage_deposit(OUTER_SET_ID, &alice);
+
+    let deposit = |token_id: String, set_id: ValidAccountId, amount: u128| {
+        alice
+            .call(
+                token_id,
+                "ft_transfer_call",
+                &near_sdk::serde_json::json!({
+                    "receiver_id": set_id,
+                    "amount": U128(amount),
+                    "msg": format!("{{\"sender_id\":\"{}\"}}", alice.account_id()),
+                })
+                .to_string()
+                .into_bytes(),
+                near_sdk_sim::DEFAULT_GAS,
+                1,
+            )
+            .assert_success();
+    };
+    fts.iter().for_each(|ft| {
+        call!(root, ft.ft_transfer(alice.valid_account_id(), 500.into(), None), deposit = 1)
+            .assert_success();
+    });
+
+    // 100 inner set tokens from 100 ft-0 and 200 ft-1
+    deposit(ft_ids[0].clone(), inner.valid_account_id(), 100);
+    deposit(ft_ids[1].clone(), inner.valid_account_id(), 200);
+    call!(alice, inner.wrap(None, None), deposit = 1).assert_success();
+
+    // 50 outer set tokens from 100 inner set tokens and 50 ft-1
+    deposit(inner.account_id(), outer.valid_account_id(), 100);
+    deposit(ft_ids[1].clone(), outer.valid_account_id(), 50);
+    call!(alice, outer.wrap(None, None), deposit = 1).assert_success();
+    let outer_balance: U128 = view!(outer.ft_balance_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(outer_balance.0, 50);
+
+    // 10 outer set tokens need 20 inner set tokens, i.e. 20 ft-0 and 40 ft-1, and 10 more ft-1
+    let quote: Vec<TokenAmount> =
+        call!(alice, outer.quote_required_underlying(U128(10))).unwrap_json();
+    let amount_of = |token_id: &String| {
+        quote.iter().find(|token| &token.token_id == token_id).map(|token| token.amount.0)
+    };
+    assert_eq!(quote.len(), 2);
+    assert_eq!(amount_of(&ft_ids[0]), Some(20));
+    assert_eq!(amount_of(&ft_ids[1]), Some(50));
+
+    // Unwrapping 10 outer set tokens credits alice's base tokens in both sets
+    call!(alice, outer.unwrap_nested(Some(10), None), deposit = 1).assert_success();
+    let outer_supply: U128 = view!(outer.ft_total_supply()).unwrap_json();
+    assert_eq!(outer_supply.0, 40);
+    let inner_held: U128 = view!(inner.ft_balance_of(outer.valid_account_id())).unwrap_json();
+    assert_eq!(inner_held.0, 80);
+
+    let internal_balance = |set: &near_sdk_sim::ContractAccount<_>, token_id: &String| -> u128 {
+        let balance: U128 = view!(set.internal_balance_of(
+            alice.valid_account_id(),
+            ValidAccountId::try_from(token_id.clone()).unwrap()
+        ))
+        .unwrap_json();
+        balance.0
+    };
+    assert_eq!(internal_balance(&outer, &ft_ids[1]), 10);
+    assert_eq!(internal_balance(&outer, &inner.account_id()), 0);
+    assert_eq!(internal_balance(&inner, &ft_ids[0]), 20);
+    assert_eq!(internal_balance(&inner, &ft_ids[1]), 40);
+}
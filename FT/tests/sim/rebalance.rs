@@ -0,0 +1,86 @@
+use amm::Price;
+use near_sdk::json_types::{U128, U64};
+use near_sdk_sim::{call, view};
+use token_set_fungible_token::{RebalanceConfig, Role, SwapLeg, TokenWithRatio};
+
+use crate::utils::{init_amm, init_with_macros as init};
+
+#[test]
+fn simulate_rebalance_through_amm() {
+    let initial_balance = 1_000_000_000;
+    let deposit = 1_000_000;
+    let (root, owner_bob, token_set, _, fts, alice) =
+        init(vec![1_000, 1_000], None, None, initial_balance);
+    // ft-0 is worth twice as much as ft-1
+    let amm = init_amm(
+        &root,
+        &fts,
+        vec![
+            Price { multiplier: U128(2), decimals: 0 },
+            Price { multiplier: U128(1), decimals: 0 },
+        ],
+    );
+    call!(root, fts[1].ft_transfer(amm.valid_account_id(), deposit.into(), None), deposit = 1)
+        .assert_success();
+
+    fts.iter().for_each(|ft| {
+        call!(root, ft.ft_transfer(alice.valid_account_id(), deposit.into(), None), deposit = 1)
+            .assert_success();
+        call!(
+            alice,
+            ft.ft_transfer_call(
+                token_set.valid_account_id(),
+                deposit.into(),
+                None,
+                format!("{{\"sender_id\":\"{}\"}}", alice.account_id())
+            ),
+            deposit = 1
+        )
+        .assert_success();
+    });
+    call!(alice, token_set.wrap(None, None), deposit = 1).assert_success();
+
+    call!(
+        owner_bob,
+        token_set.set_rebalance_config(amm.valid_account_id(), 1_000, U64(0)),
+        deposit = 1
+    )
+    .assert_success();
+    call!(owner_bob, token_set.set_target_weights(vec![5_000, 5_000]), deposit = 1)
+        .assert_success();
+    call!(owner_bob, token_set.set_rebalance_executor(amm.valid_account_id(), 100), deposit = 1)
+        .assert_success();
+
+    // Only the owner and rebalancers can start the rebalance, which pauses wrapping
+    let outcome = call!(alice, token_set.rebalance(), deposit = 1);
+    assert!(!outcome.is_ok(), "Should reject a rebalance started by a holder");
+    call!(owner_bob, token_set.grant_role(Role::Rebalancer, alice.valid_account_id()), deposit = 1)
+        .assert_success();
+    call!(alice, token_set.rebalance(), deposit = 1).assert_success();
+    let config: RebalanceConfig = view!(token_set.get_rebalance_config()).unwrap_json();
+    assert_eq!(config.pending_target_ratios, Some(vec![750, 1_500]));
+
+    // 250 ft-0 per set token are sold, worth 500 ft-1 at the oracle price
+    let leg = |min_amount_out: u128| SwapLeg {
+        pool_id: 0,
+        token_in: fts[0].valid_account_id(),
+        token_out: fts[1].valid_account_id(),
+        amount_in: U128(250_000),
+        min_amount_out: U128(min_amount_out),
+    };
+    let outcome = call!(alice, token_set.execute_rebalance(vec![leg(400_000)]));
+    assert!(!outcome.is_ok(), "Should reject a min_amount_out beyond the slippage");
+
+    call!(alice, token_set.execute_rebalance(vec![leg(500_000)])).assert_success();
+
+    let ratios: Vec<TokenWithRatio> = view!(token_set.get_set_ratios()).unwrap_json();
+    assert_eq!(ratios.iter().map(|r| r.ratio).collect::<Vec<u32>>(), vec![750, 1_500]);
+    let config: RebalanceConfig = view!(token_set.get_rebalance_config()).unwrap_json();
+    assert_eq!(config.pending_target_ratios, None);
+    assert_eq!(config.nonce.0, 1);
+
+    let reserve_0: U128 = view!(fts[0].ft_balance_of(token_set.valid_account_id())).unwrap_json();
+    let reserve_1: U128 = view!(fts[1].ft_balance_of(token_set.valid_account_id())).unwrap_json();
+    assert_eq!(reserve_0.0, deposit - 250_000);
+    assert_eq!(reserve_1.0, deposit + 500_000);
+}
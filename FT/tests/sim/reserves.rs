@@ -0,0 +1,22 @@
+use near_sdk_sim::{call, view};
+use token_set_fungible_token::{BackingReport, TokenAmount};
+
+use crate::utils::{deposit_and_wrap, init_with_macros as init};
+
+#[test]
+fn simulate_verify_backing() {
+    let (root, _, token_set, _, fts, alice) = init(vec![1, 2], None, None, 1_000);
+    deposit_and_wrap(&root, &alice, &token_set, &fts);
+    call!(alice, token_set.unwrap(Some(40), None), deposit = 1).assert_success();
+
+    let reserves: Vec<TokenAmount> = view!(token_set.get_reserves()).unwrap_json();
+    let reserves: Vec<u128> = reserves.iter().map(|reserve| reserve.amount.0).collect();
+    assert_eq!(reserves, vec![60, 120]);
+
+    let report: BackingReport = call!(alice, token_set.verify_backing()).unwrap_json();
+    assert!(report.backed);
+    assert_eq!(report.components[1].required.0, 120);
+    // The unwrapped tokens are still held as alice's internal balances, on top of the reserve
+    assert_eq!(report.components[1].internal.0, 80);
+    assert_eq!(report.components[1].balance.map(|balance| balance.0), Some(200));
+}
@@ -0,0 +1,54 @@
+use near_sdk::json_types::U128;
+use near_sdk_sim::{call, view};
+use token_set_fungible_token::{SkimDestination, TokenWithRatio};
+
+use crate::utils::{deposit_and_wrap, init_with_macros as init};
+
+#[test]
+fn simulate_skim() {
+    let (root, owner_bob, token_set, _, fts, alice) = init(vec![1, 2], None, None, 1_000);
+    deposit_and_wrap(&root, &alice, &token_set, &fts);
+    // Sent without `ft_transfer_call`, nobody is credited
+    call!(root, fts[0].ft_transfer(token_set.valid_account_id(), U128(250), None), deposit = 1)
+        .assert_success();
+    let accounted: U128 =
+        view!(token_set.get_accounted_balance(fts[0].valid_account_id())).unwrap_json();
+    assert_eq!(accounted.0, 100);
+
+    let outcome = call!(
+        alice,
+        token_set.skim(fts[0].valid_account_id(), U128(50), SkimDestination::Treasury),
+        deposit = 1
+    );
+    assert!(!outcome.is_ok(), "Only the owner can skim");
+
+    // More than the surplus is rejected when the balance is read
+    call!(
+        owner_bob,
+        token_set.skim(fts[0].valid_account_id(), U128(300), SkimDestination::Treasury),
+        deposit = 1
+    );
+    let balance: U128 = view!(
+        token_set.internal_balance_of(owner_bob.valid_account_id(), fts[0].valid_account_id())
+    )
+    .unwrap_json();
+    assert_eq!(balance.0, 0);
+
+    let destination = SkimDestination::Claimant { account_id: root.valid_account_id() };
+    call!(owner_bob, token_set.skim(fts[0].valid_account_id(), U128(50), destination), deposit = 1)
+        .assert_success();
+    let balance: U128 =
+        view!(token_set.internal_balance_of(root.valid_account_id(), fts[0].valid_account_id()))
+            .unwrap_json();
+    assert_eq!(balance.0, 50);
+
+    // The remaining 200 back each of alice's 100 set tokens with 2 more ft-0
+    call!(
+        owner_bob,
+        token_set.skim(fts[0].valid_account_id(), U128(200), SkimDestination::Backing),
+        deposit = 1
+    )
+    .assert_success();
+    let ratios: Vec<TokenWithRatio> = view!(token_set.get_set_ratios()).unwrap_json();
+    assert_eq!(ratios.iter().map(|r| r.ratio).collect::<Vec<u32>>(), vec![3, 2]);
+}
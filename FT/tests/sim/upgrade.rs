@@ -0,0 +1,114 @@
+use near_sdk::borsh::BorshSerialize;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk_sim::{view, ContractAccount, UserAccount, DEFAULT_GAS, STORAGE_AMOUNT};
+use token_set_fungible_token::{ContractContract as TokenSetContract, StateVersion, TokenWithRatio};
+
+use crate::utils::{
+    init_with_macros as init, register_user, storage_deposit, TOKEN_SET_V1_WASM_BYTES,
+    TOKEN_SET_WASM_BYTES,
+};
+
+const V1_SET_ID: &str = "token-set-v1";
+
+fn call_v1(user: &UserAccount, method: &str, args: near_sdk::serde_json::Value, deposit: u128) {
+    user.call(
+        V1_SET_ID.to_string(),
+        method,
+        &args.to_string().into_bytes(),
+        DEFAULT_GAS,
+        deposit,
+    )
+    .assert_success();
+}
+
+#[test]
+fn simulate_upgrade_from_v1() {
+    let (root, owner_bob, _, _, fts, alice) = init(vec![1, 2], None, None, 1_000);
+    let ft_ids: Vec<String> = fts.iter().map(|ft| ft.account_id()).collect();
+
+    // Deploy and use the first release
+    let v1 = root.deploy(&TOKEN_SET_V1_WASM_BYTES, V1_SET_ID.to_string(), STORAGE_AMOUNT);
+    call_v1(
+        &v1,
+        "new_default_meta",
+        json!({
+            "owner_id": owner_bob.account_id(),
+            "name": "V1 SET",
+            "symbol": "V1",
+            "icon_url": null,
+            "set_ratios": [
+                { "token_id": ft_ids[0], "ratio": 1 },
+                { "token_id": ft_ids[1], "ratio": 2 },
+            ],
+            "platform_fee": "0",
+            "platform_id": root.account_id(),
+            "owner_fee": "0",
+        }),
+        0,
+    );
+    register_user(&ft_ids, &v1);
+    storage_deposit(V1_SET_ID, &alice);
+    storage_deposit(V1_SET_ID, &root);
+    storage_deposit(V1_SET_ID, &owner_bob);
+    for (i, ft_id) in ft_ids.iter().enumerate() {
+        let amount = U128(100 * (i as u128 + 1));
+        root.call(
+            ft_id.clone(),
+            "ft_transfer",
+            &json!({ "receiver_id": alice.account_id(), "amount": amount })
+                .to_string()
+                .into_bytes(),
+            DEFAULT_GAS,
+            1,
+        )
+        .assert_success();
+        alice
+            .call(
+                ft_id.clone(),
+                "ft_transfer_call",
+                &json!({
+                    "receiver_id": V1_SET_ID,
+                    "amount": amount,
+                    "msg": format!("{{\"sender_id\":\"{}\"}}", alice.account_id()),
+                })
+                .to_string()
+                .into_bytes(),
+                DEFAULT_GAS,
+                1,
+            )
+            .assert_success();
+    }
+    call_v1(&alice, "wrap", json!({}), 1);
+
+    // Deploy the current code the way a V1 contract is upgraded, through its own access key
+    v1.create_transaction(V1_SET_ID.to_string())
+        .deploy_contract(TOKEN_SET_WASM_BYTES.to_vec())
+        .function_call("migrate".to_string(), vec![], DEFAULT_GAS, 0)
+        .submit()
+        .assert_success();
+
+    let token_set = ContractAccount {
+        user_account: v1,
+        contract: TokenSetContract { account_id: V1_SET_ID.to_string() },
+    };
+    let check_state = || {
+        let version: StateVersion = view!(token_set.get_state_version()).unwrap_json();
+        assert_eq!(version, StateVersion::V2);
+        let balance: U128 = view!(token_set.ft_balance_of(alice.valid_account_id())).unwrap_json();
+        assert_eq!(balance.0, 100);
+        let total_supply: U128 = view!(token_set.ft_total_supply()).unwrap_json();
+        assert_eq!(total_supply.0, 100);
+        let ratios: Vec<TokenWithRatio> = view!(token_set.get_set_ratios()).unwrap_json();
+        assert_eq!(ratios.iter().map(|r| r.ratio).collect::<Vec<u32>>(), vec![1, 2]);
+        assert!(ratios.iter().all(|r| !r.is_set));
+    };
+    check_state();
+
+    // From now on the owner upgrades through `upgrade`
+    let code = TOKEN_SET_WASM_BYTES.to_vec().try_to_vec().unwrap();
+    let outcome = alice.call(V1_SET_ID.to_string(), "upgrade", &code, DEFAULT_GAS, 1);
+    assert!(!outcome.is_ok(), "Only the owner can upgrade");
+    owner_bob.call(V1_SET_ID.to_string(), "upgrade", &code, DEFAULT_GAS, 1).assert_success();
+    check_state();
+}
@@ -1,25 +1,55 @@
 use std::convert::TryFrom;
 
+use amm::{AmmContract, Price};
 use defi::DeFiContract;
 use fungible_token::ContractContract as FtContract;
 use near_sdk::AccountId;
-use token_set_fungible_token::{ContractContract as TokenSetContract, TokenWithRatioValid};
+use token_set_fungible_token::{
+    AccountClosedPolicy, ContractContract as TokenSetContract, TokenWithRatioValid,
+};
+use wnear::ContractContract as WnearContract;
 
 use near_sdk::json_types::{ValidAccountId, U128};
 use near_sdk::serde_json::json;
 use near_sdk_sim::{
-    deploy, init_simulator, to_yocto, ContractAccount, UserAccount, DEFAULT_GAS, STORAGE_AMOUNT,
+    call, deploy, init_simulator, to_yocto, ContractAccount, UserAccount, DEFAULT_GAS,
+    STORAGE_AMOUNT,
 };
 
 // Load in contract bytes at runtime
 near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
-    TOKEN_SET_WASM_BYTES => "res/token_set_fungible_token.wasm",
+    pub TOKEN_SET_WASM_BYTES => "res/token_set_fungible_token.wasm",
+    // The first release, kept to test upgrades
+    pub TOKEN_SET_V1_WASM_BYTES => "res/token_set_fungible_token_v1.wasm",
+}
+
+near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
     FT_WASM_BYTES => "res/fungible_token.wasm",
     DEFI_WASM_BYTES => "res/defi.wasm",
+    AMM_WASM_BYTES => "res/amm.wasm",
+    WNEAR_WASM_BYTES => "res/wnear.wasm",
 }
 
 const TOKEN_SET_ID: &str = "token-set";
 const DEFI_ID: &str = "defi";
+const AMM_ID: &str = "amm";
+const WNEAR_ID: &str = "wrap";
+
+// Register the given `user` with the contract `contract_id`
+pub fn storage_deposit(contract_id: &str, user: &near_sdk_sim::UserAccount) {
+    user.call(
+        contract_id.to_string(),
+        "storage_deposit",
+        &json!({
+            "account_id": user.valid_account_id()
+        })
+        .to_string()
+        .into_bytes(),
+        near_sdk_sim::DEFAULT_GAS / 2,
+        near_sdk::env::storage_byte_cost() * 1000, // attached deposit
+    )
+    .assert_success();
+}
 
 // Register the given `user` with FT contract
 pub fn register_user(ft_ids: &Vec<AccountId>, user: &near_sdk_sim::UserAccount) {
@@ -88,6 +118,23 @@ pub fn init_with_macros(
     ContractAccount<DeFiContract>,
     Vec<ContractAccount<FtContract>>,
     UserAccount,
+) {
+    init_with_policy(ratios, platform_fee, owner_fee, init_token_supply, None)
+}
+
+pub fn init_with_policy(
+    ratios: Vec<u32>,
+    platform_fee: Option<u128>,
+    owner_fee: Option<u128>,
+    init_token_supply: u128,
+    account_closed_policy: Option<AccountClosedPolicy>,
+) -> (
+    UserAccount,
+    UserAccount,
+    ContractAccount<TokenSetContract>,
+    ContractAccount<DeFiContract>,
+    Vec<ContractAccount<FtContract>>,
+    UserAccount,
 ) {
     let root = init_simulator(None);
     let name = "YOUR MOM TOKEN".to_string();
@@ -122,6 +169,7 @@ pub fn init_with_macros(
         .map(|(i, ft_c)| TokenWithRatioValid {
             token_id: ValidAccountId::try_from(ft_c.account_id()).unwrap(),
             ratio: ratios[i],
+            is_set: false,
         })
         .collect();
 
@@ -146,7 +194,9 @@ pub fn init_with_macros(
             ratios,
             U128::from(platform_fee.unwrap_or(0)),
             root.valid_account_id(),
-            U128::from(owner_fee.unwrap_or(0))
+            U128::from(owner_fee.unwrap_or(0)),
+            account_closed_policy,
+            None
         )
     );
     let alice = root.create_user("alice".to_string(), to_yocto("100"));
@@ -167,3 +217,88 @@ pub fn init_with_macros(
 
     (root, owner_bob, token_set, defi, ft_contracts, alice)
 }
+
+/// Deploy the mock AMM, register it with the given tokens and set their prices
+pub fn init_amm(
+    root: &UserAccount,
+    fts: &Vec<ContractAccount<FtContract>>,
+    prices: Vec<Price>,
+) -> ContractAccount<AmmContract> {
+    let amm = deploy!(
+        contract: AmmContract,
+        contract_id: AMM_ID,
+        bytes: &AMM_WASM_BYTES,
+        signer_account: root,
+        init_method: new(root.valid_account_id(), 0)
+    );
+    let ft_ids: Vec<AccountId> = fts.iter().map(|ft| ft.account_id()).collect();
+    register_user(&ft_ids, &amm.user_account);
+    fts.iter().zip(prices.into_iter()).for_each(|(ft, price)| {
+        call!(root, amm.set_price(ft.valid_account_id(), price)).assert_success();
+    });
+    amm
+}
+
+/// Deploy the mock wNEAR contract
+pub fn init_wnear(root: &UserAccount) -> ContractAccount<WnearContract> {
+    deploy!(
+        contract: WnearContract,
+        contract_id: WNEAR_ID,
+        bytes: &WNEAR_WASM_BYTES,
+        signer_account: root,
+        init_method: new()
+    )
+}
+
+/// Deploy another token set at `contract_id` with the given components and no fees
+pub fn init_token_set(
+    root: &UserAccount,
+    contract_id: &str,
+    owner: &UserAccount,
+    ratios: Vec<TokenWithRatioValid>,
+) -> ContractAccount<TokenSetContract> {
+    deploy!(
+        contract: TokenSetContract,
+        contract_id: contract_id,
+        bytes: &TOKEN_SET_WASM_BYTES,
+        signer_account: root,
+        init_method: new_default_meta(
+            owner.valid_account_id(),
+            contract_id.to_string(),
+            contract_id.to_uppercase(),
+            None,
+            ratios,
+            U128::from(0),
+            root.valid_account_id(),
+            U128::from(0),
+            None,
+            None
+        )
+    )
+}
+
+/// Give `user` 100 ft-0 and 200 ft-1 and wrap them into 100 set tokens
+pub fn deposit_and_wrap(
+    root: &UserAccount,
+    user: &UserAccount,
+    token_set: &ContractAccount<TokenSetContract>,
+    fts: &Vec<ContractAccount<FtContract>>,
+) {
+    fts.iter().enumerate().for_each(|(i, ft)| {
+        let amount = U128(100 * (i as u128 + 1));
+        call!(root, ft.ft_transfer(user.valid_account_id(), amount, None), deposit = 1)
+            .assert_success();
+        call!(
+            user,
+            ft.ft_transfer_call(
+                token_set.valid_account_id(),
+                amount,
+                None,
+                format!("{{\"sender_id\":\"{}\"}}", user.account_id())
+            ),
+            deposit = 1
+        )
+        .assert_success();
+    });
+    call!(user, token_set.wrap(None, None), deposit = 1).assert_success();
+}
@@ -46,7 +46,7 @@ fn simulate_wrapping() {
         )
         .assert_success();
     });
-    call!(alice, token_set.wrap(None), deposit = 1).assert_success();
+    call!(alice, token_set.wrap(None, None), deposit = 1).assert_success();
 
     let amount_minted = initial_balance / 4;
     let expected_root = amount_minted * 1_000 / 100_000;
@@ -0,0 +1,98 @@
+//! Restricting who can mint, and optionally who can hold, the set token.
+//!
+//! Both modes check the same allowlist, which the owner and the accounts with the
+//! `Role::Allowlist` role maintain. In mint mode only allowlisted accounts can wrap, while the
+//! set tokens can still be transferred to anyone. In transfer mode both the sender and the
+//! receiver of a transfer have to be allowlisted.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupSet;
+use near_sdk::json_types::ValidAccountId;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, AccountId};
+
+use crate::roles::Role;
+use crate::utils;
+use crate::Contract;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AccessControl {
+    pub(crate) allowlist: LookupSet<AccountId>,
+    pub(crate) mint_restricted: bool,
+    pub(crate) transfer_restricted: bool,
+}
+
+impl AccessControl {
+    pub(crate) fn new() -> Self {
+        Self {
+            allowlist: LookupSet::new(b"access".to_vec()),
+            mint_restricted: false,
+            transfer_restricted: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccessModes {
+    pub mint_restricted: bool,
+    pub transfer_restricted: bool,
+}
+
+impl Contract {
+    pub(crate) fn assert_can_mint(&self, account_id: &AccountId) {
+        if self.access.mint_restricted && !self.access.allowlist.contains(account_id) {
+            panic!("{} is not allowed to mint", account_id);
+        }
+    }
+
+    pub(crate) fn assert_can_transfer(&self, sender_id: &AccountId, receiver_id: &AccountId) {
+        if !self.access.transfer_restricted {
+            return;
+        }
+        for account_id in [sender_id, receiver_id].iter() {
+            if !self.access.allowlist.contains(account_id) {
+                panic!("{} is not allowed to hold the set token", account_id);
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn set_access_modes(&mut self, modes: AccessModes) {
+        utils::assert_1_yocto();
+        self.assert_owner_or_role(Role::Allowlist);
+        self.access.mint_restricted = modes.mint_restricted;
+        self.access.transfer_restricted = modes.transfer_restricted;
+    }
+
+    #[payable]
+    pub fn add_to_allowlist(&mut self, account_ids: Vec<ValidAccountId>) {
+        utils::assert_1_yocto();
+        self.assert_owner_or_role(Role::Allowlist);
+        for account_id in account_ids {
+            self.access.allowlist.insert(account_id.as_ref());
+        }
+    }
+
+    #[payable]
+    pub fn remove_from_allowlist(&mut self, account_ids: Vec<ValidAccountId>) {
+        utils::assert_1_yocto();
+        self.assert_owner_or_role(Role::Allowlist);
+        for account_id in account_ids {
+            self.access.allowlist.remove(account_id.as_ref());
+        }
+    }
+
+    pub fn is_allowlisted(&self, account_id: ValidAccountId) -> bool {
+        self.access.allowlist.contains(account_id.as_ref())
+    }
+
+    pub fn get_access_modes(&self) -> AccessModes {
+        AccessModes {
+            mint_restricted: self.access.mint_restricted,
+            transfer_restricted: self.access.transfer_restricted,
+        }
+    }
+}
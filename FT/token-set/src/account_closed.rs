@@ -0,0 +1,124 @@
+//! What happens to the set tokens of an account which is force unregistered.
+//!
+//! Internal balances are only kept for registered accounts, so the underlying tokens of a closed
+//! account are not credited right away: its set tokens are escrowed until it registers again.
+//! Donations to the holders are kept per component and per set token, on top of the ratios, so
+//! that nothing is lost to rounding and unclaimed set tokens get their share as well.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::utils;
+use crate::{events, Contract};
+
+/// Chosen by the owner at init
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AccountClosedPolicy {
+    /// Burn the set tokens and credit the underlying tokens to the platform
+    Platform,
+    /// Keep the set tokens aside until the closed account claims them back, which burns them
+    /// and credits it the underlying tokens
+    Underlying,
+    /// Keep the set tokens aside until the closed account claims them back
+    Escrow,
+    /// Burn the set tokens and add their backing to the remaining set tokens, so that the holders
+    /// share the underlying tokens pro-rata
+    DonateToHolders,
+}
+
+impl Default for AccountClosedPolicy {
+    fn default() -> Self {
+        AccountClosedPolicy::Platform
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct DonationEvent<'a> {
+    account_id: &'a AccountId,
+    amount: U128,
+    /// The backing of each component donated per set token so far, scaled by 10^12
+    donated_backing: Vec<U128>,
+}
+
+impl Contract {
+    /// Called once the account's `balance` set tokens were removed from the total supply
+    pub(crate) fn handle_account_closed(&mut self, account_id: AccountId, balance: Balance) {
+        if balance == 0 {
+            return;
+        }
+        // The supply and backing a pending rebalance swaps for would change
+        self.rebalancer.assert_not_pending();
+        match self.account_closed_policy {
+            AccountClosedPolicy::Platform => self.burn_to_platform(balance),
+            AccountClosedPolicy::Underlying | AccountClosedPolicy::Escrow => {
+                let escrowed = self.escrowed.get(&account_id).unwrap_or(0);
+                self.escrowed.insert(&account_id, &(escrowed + balance));
+                self.total_escrowed += balance;
+            }
+            AccountClosedPolicy::DonateToHolders => {
+                // Escrowed set tokens and referral rewards are backed as well
+                let backed_supply = self.token.total_supply + self.unclaimed_supply();
+                if backed_supply == 0 {
+                    log!("No holders are left to donate to");
+                    return self.burn_to_platform(balance);
+                }
+                if !self.set_info.donate(balance, backed_supply) {
+                    log!("The donation is too large to be shared");
+                    return self.burn_to_platform(balance);
+                }
+                let donated_backing =
+                    self.set_info.donated_backing().into_iter().map(U128).collect();
+                events::emit(
+                    "donate_to_holders",
+                    &DonationEvent {
+                        account_id: &account_id,
+                        amount: balance.into(),
+                        donated_backing,
+                    },
+                );
+            }
+        }
+    }
+
+    fn burn_to_platform(&mut self, balance: Balance) {
+        let platform_id = self.set_info.fee.platform_id.clone();
+        self.set_info.on_burn(&mut self.balances, platform_id, balance);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Claim back the set tokens escrowed when the caller's account was force unregistered. The
+    /// caller is registered again, paying the storage from the attached deposit. With the
+    /// `Underlying` policy, the caller is credited the underlying tokens instead.
+    #[payable]
+    pub fn claim_escrow(&mut self) -> U128 {
+        utils::assert_at_least_1_yocto();
+        let caller = env::predecessor_account_id();
+        let amount = self.escrowed.remove(&caller).expect("Nothing is escrowed for the caller");
+        let storage_cost = self.register_with_deposit(&caller, env::attached_deposit());
+        self.total_escrowed -= amount;
+        if self.account_closed_policy == AccountClosedPolicy::Underlying {
+            // The escrowed set tokens were already taken out of the supply
+            self.rebalancer.assert_not_pending();
+            self.set_info.on_burn(&mut self.balances, caller, amount);
+        } else {
+            self.checkpointed(&[caller.clone()], |this| {
+                this.token.internal_deposit(&caller, amount)
+            });
+        }
+        utils::refund_deposit(env::attached_deposit() - storage_cost);
+        amount.into()
+    }
+
+    pub fn escrowed_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        self.escrowed.get(account_id.as_ref()).unwrap_or(0).into()
+    }
+
+    pub fn get_account_closed_policy(&self) -> AccountClosedPolicy {
+        self.account_closed_policy
+    }
+}
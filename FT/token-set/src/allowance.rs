@@ -0,0 +1,109 @@
+//! Allowances on the set token, letting protocols pull set tokens without `ft_transfer_call`.
+//!
+//! An owner approves a spender for an amount with `ft_approve` and pays for the storage of the
+//! approval. The spender then moves up to that amount with `ft_transfer_from`, which goes through
+//! the same transfer checks as `ft_transfer`. An allowance used up or set to zero is removed and
+//! its storage refunded to the owner.
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::Serialize;
+use near_sdk::{env, near_bindgen, AccountId, Balance, Promise};
+
+use crate::utils;
+use crate::{events, Contract};
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct ApprovalEvent<'a> {
+    owner_id: &'a AccountId,
+    spender_id: &'a AccountId,
+    /// The allowance left
+    amount: U128,
+}
+
+impl Contract {
+    /// Store the allowance, removing it at zero.
+    ///
+    /// return the cost of the storage released
+    fn internal_set_allowance(
+        &mut self,
+        owner_id: &AccountId,
+        spender_id: &AccountId,
+        amount: Balance,
+    ) -> Balance {
+        let initial_storage_usage = env::storage_usage();
+        let key = (owner_id.clone(), spender_id.clone());
+        if amount == 0 {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(&key, &amount);
+        }
+        initial_storage_usage.saturating_sub(env::storage_usage()) as Balance
+            * env::storage_byte_cost()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Allow `spender_id` to transfer up to `amount` of the caller's set tokens, replacing any
+    /// previous allowance. The caller pays for the storage and gets it back at zero.
+    #[payable]
+    pub fn ft_approve(&mut self, spender_id: ValidAccountId, amount: U128) {
+        utils::assert_at_least_1_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let owner_id = env::predecessor_account_id();
+        self.assert_registered(&owner_id);
+        if spender_id.as_ref() == &owner_id {
+            panic!("The owner cannot approve itself");
+        }
+        let released = self.internal_set_allowance(&owner_id, spender_id.as_ref(), amount.0);
+        if env::storage_usage() > initial_storage_usage {
+            utils::charge_storage(initial_storage_usage);
+        } else {
+            utils::refund_deposit(env::attached_deposit() + released);
+        }
+        let event = ApprovalEvent { owner_id: &owner_id, spender_id: spender_id.as_ref(), amount };
+        events::emit("ft_approve", &event);
+    }
+
+    pub fn ft_allowance(&self, owner_id: ValidAccountId, spender_id: ValidAccountId) -> U128 {
+        let key = (owner_id.into(), spender_id.into());
+        self.allowances.get(&key).unwrap_or(0).into()
+    }
+
+    /// Transfer `amount` of the set tokens of `owner_id`, who approved the caller for at least
+    /// as much, to `receiver_id`
+    #[payable]
+    pub fn ft_transfer_from(
+        &mut self,
+        owner_id: ValidAccountId,
+        receiver_id: ValidAccountId,
+        amount: U128,
+        memo: Option<String>,
+    ) {
+        utils::assert_1_yocto();
+        let spender_id = env::predecessor_account_id();
+        let owner_id: AccountId = owner_id.into();
+        let receiver_id: AccountId = receiver_id.into();
+        let key = (owner_id.clone(), spender_id.clone());
+        let allowance = self.allowances.get(&key).unwrap_or(0);
+        if allowance < amount.0 {
+            panic!(
+                "The allowance of {} is {}, cannot transfer {}",
+                spender_id, allowance, amount.0
+            );
+        }
+        self.assert_can_transfer(&owner_id, &receiver_id);
+        let account_ids = [owner_id.clone(), receiver_id.clone()];
+        self.checkpointed(&account_ids, |this| {
+            this.token.internal_transfer(&owner_id, &receiver_id, amount.0, memo)
+        });
+        let left = allowance - amount.0;
+        let released = self.internal_set_allowance(&owner_id, &spender_id, left);
+        if released > 0 {
+            Promise::new(owner_id.clone()).transfer(released);
+        }
+        let event =
+            ApprovalEvent { owner_id: &owner_id, spender_id: &spender_id, amount: left.into() };
+        events::emit("ft_transfer_from", &event);
+    }
+}
@@ -0,0 +1,180 @@
+//! Vetting of the set's components before anything can be wrapped.
+//!
+//! At init every component is probed with `ft_metadata`, so that accounts which are not fungible
+//! tokens are caught. If the set has an allowlist, every component also has to be on it: either
+//! on a registry contract answering `is_token_allowed`, or on the list the platform keeps in this
+//! contract. A set with unknown components can only be used once the platform approves them.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupSet;
+use near_sdk::json_types::ValidAccountId;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, Promise, PromiseResult,
+};
+
+use crate::utils;
+use crate::Contract;
+
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_IS_TOKEN_ALLOWED: Gas = 5_000_000_000_000;
+
+/// Where the vetted token contracts are listed, chosen at init
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ComponentAllowlist {
+    /// A contract answering `is_token_allowed(token_id)`
+    Registry { registry_id: AccountId },
+    /// The tokens the platform added to this contract
+    Local,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ComponentsStatus {
+    /// Waiting for the components to be probed
+    Pending,
+    Verified,
+    /// Some component is not a fungible token or not on the allowlist
+    Rejected,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ComponentVerification {
+    pub(crate) allowlist: Option<ComponentAllowlist>,
+    pub(crate) allowed_tokens: LookupSet<AccountId>,
+    pub(crate) status: ComponentsStatus,
+}
+
+impl ComponentVerification {
+    pub(crate) fn new(allowlist: Option<ComponentAllowlist>, status: ComponentsStatus) -> Self {
+        Self { allowlist, allowed_tokens: LookupSet::new(b"allow".to_vec()), status }
+    }
+}
+
+#[ext_contract(ext_component_registry)]
+pub trait ComponentRegistry {
+    fn is_token_allowed(&self, token_id: AccountId) -> bool;
+}
+
+impl Contract {
+    pub(crate) fn assert_components_verified(&self) {
+        if self.verification.status != ComponentsStatus::Verified {
+            panic!("The set's components are not verified");
+        }
+    }
+
+    /// The registry queries to run alongside the components' `ft_metadata` probes
+    pub(crate) fn registry_queries(&self, token_ids: &[AccountId]) -> Vec<Promise> {
+        match &self.verification.allowlist {
+            Some(ComponentAllowlist::Registry { registry_id }) => token_ids
+                .iter()
+                .map(|token_id| {
+                    ext_component_registry::is_token_allowed(
+                        token_id.clone(),
+                        registry_id,
+                        NO_DEPOSIT,
+                        GAS_FOR_IS_TOKEN_ALLOWED,
+                    )
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Update the status from the probes' results: `is_token[i]` tells whether the i-th
+    /// component answered `ft_metadata`, the registry's answers follow the probes' results
+    pub(crate) fn on_components_probed(&mut self, token_ids: &[AccountId], is_token: &[bool]) {
+        if self.verification.status == ComponentsStatus::Verified {
+            return;
+        }
+        let mut verified = true;
+        for (i, token_id) in token_ids.iter().enumerate() {
+            let allowed = match &self.verification.allowlist {
+                None => true,
+                Some(ComponentAllowlist::Local) => {
+                    self.verification.allowed_tokens.contains(token_id)
+                }
+                Some(ComponentAllowlist::Registry { .. }) => {
+                    match env::promise_result((token_ids.len() + i) as u64) {
+                        PromiseResult::Successful(value) => {
+                            serde_json::from_slice::<bool>(&value).unwrap_or(false)
+                        }
+                        _ => false,
+                    }
+                }
+            };
+            if !is_token[i] {
+                log!("{} is not a fungible token", token_id);
+            } else if !allowed {
+                log!("{} is not on the allowlist", token_id);
+            }
+            verified &= is_token[i] && allowed;
+        }
+        self.verification.status =
+            if verified { ComponentsStatus::Verified } else { ComponentsStatus::Rejected };
+    }
+
+    /// Check `token_ids` against the local allowlist. Components on a registry are vetted by the
+    /// platform instead, as it cannot be queried synchronously.
+    pub(crate) fn assert_components_allowed(&self, token_ids: &[AccountId]) {
+        if let Some(ComponentAllowlist::Local) = &self.verification.allowlist {
+            for token_id in token_ids {
+                if !self.verification.allowed_tokens.contains(token_id) {
+                    panic!("{} is not on the allowlist", token_id);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn assert_platform(&self) {
+        if env::predecessor_account_id() != self.set_info.fee.platform_id {
+            panic!("Only the platform can call this method");
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Probe the components again, e.g. after they were added to the allowlist
+    pub fn verify_components(&self) -> Promise {
+        if self.verification.status == ComponentsStatus::Verified {
+            panic!("The set's components are already verified");
+        }
+        self.fetch_component_metadata()
+    }
+
+    /// Let the set be used although some of its components could not be verified
+    #[payable]
+    pub fn approve_components(&mut self) {
+        utils::assert_1_yocto();
+        self.assert_platform();
+        self.verification.status = ComponentsStatus::Verified;
+    }
+
+    #[payable]
+    pub fn add_allowed_tokens(&mut self, token_ids: Vec<ValidAccountId>) {
+        utils::assert_1_yocto();
+        self.assert_platform();
+        for token_id in token_ids {
+            self.verification.allowed_tokens.insert(token_id.as_ref());
+        }
+    }
+
+    #[payable]
+    pub fn remove_allowed_tokens(&mut self, token_ids: Vec<ValidAccountId>) {
+        utils::assert_1_yocto();
+        self.assert_platform();
+        for token_id in token_ids {
+            self.verification.allowed_tokens.remove(token_id.as_ref());
+        }
+    }
+
+    pub fn is_token_allowed(&self, token_id: ValidAccountId) -> bool {
+        self.verification.allowed_tokens.contains(token_id.as_ref())
+    }
+
+    pub fn get_components_status(&self) -> ComponentsStatus {
+        self.verification.status
+    }
+}
@@ -0,0 +1,107 @@
+//! Several set actions in one call, e.g. wrapping internal balances and sending the set tokens on.
+//!
+//! The actions run in order against the caller's balances, and a failing action reverts the
+//! whole call. Only the transfers of withdrawn tokens, native NEAR and NFTs happen in later
+//! receipts: a withdrawal whose transfer fails is credited back to the caller's internal balance.
+//! The batch logs a single event listing the actions and the amount each of them moved.
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance, Promise, PromiseOrValue};
+
+use crate::utils;
+use crate::{events, Contract};
+
+const ONE_YOCTO: Balance = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SetAction {
+    /// Wrap internal balances, as many set tokens as they cover if `None`
+    Wrap { amount: Option<U128>, referrer_id: Option<ValidAccountId> },
+    /// Unwrap set tokens, the whole balance if `None`
+    Unwrap { amount: Option<U128>, withdraw_near: Option<bool> },
+    /// Send internal balance of a token to the caller, all of it if `None`. Each withdrawal
+    /// costs the 1 yoctoNEAR its `ft_transfer` is called with.
+    Withdraw { token_id: ValidAccountId, amount: Option<U128> },
+    FtTransfer { receiver_id: ValidAccountId, amount: U128, memo: Option<String> },
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct BatchEvent<'a> {
+    account_id: &'a AccountId,
+    actions: &'a [SetAction],
+    /// The amount each action minted, burned, withdrew or transferred
+    amounts: Vec<U128>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Run `actions` in order for the caller. Wrapping registers the caller first if needed,
+    /// paying the storage from the attached deposit, and each withdrawal takes 1 yoctoNEAR of it.
+    /// The rest of the deposit is refunded.
+    #[payable]
+    pub fn execute_batch(&mut self, actions: Vec<SetAction>) -> PromiseOrValue<()> {
+        utils::assert_at_least_1_yocto();
+        if actions.is_empty() {
+            panic!("Expected at least one action");
+        }
+        let caller = env::predecessor_account_id();
+        let mut deposit = env::attached_deposit();
+        if actions.iter().any(|action| matches!(action, SetAction::Wrap { .. })) {
+            deposit -= self.register_with_deposit(&caller, deposit);
+        }
+
+        let mut amounts = Vec::with_capacity(actions.len());
+        let mut promises: Vec<Promise> = vec![];
+        for action in actions.iter() {
+            let amount = match action {
+                SetAction::Wrap { amount, referrer_id } => {
+                    self.assert_can_wrap(&caller);
+                    let referrer_id = referrer_id.as_ref().map(|id| id.as_ref());
+                    self.internal_wrap(&caller, amount.map(|amount| amount.0), referrer_id)
+                }
+                SetAction::Unwrap { amount, withdraw_near } => {
+                    let (amount, returned_nfts) =
+                        self.internal_unwrap(&caller, amount.map(|amount| amount.0));
+                    promises.extend(returned_nfts);
+                    if withdraw_near.unwrap_or(false) {
+                        promises.push(self.withdraw_near(caller.clone(), amount));
+                    }
+                    amount
+                }
+                SetAction::Withdraw { token_id, amount } => {
+                    if deposit < 1 + ONE_YOCTO {
+                        panic!("Each withdrawal requires 1 yoctoNEAR more to be attached");
+                    }
+                    deposit -= ONE_YOCTO;
+                    let amount = amount.map(|amount| amount.0).unwrap_or_else(|| {
+                        self.balances.get_ft_balance(&caller, token_id.as_ref())
+                    });
+                    if amount == 0 {
+                        panic!("No {} to withdraw", token_id);
+                    }
+                    promises.push(self.withdraw_underlying(&caller, token_id.as_ref(), amount));
+                    amount
+                }
+                SetAction::FtTransfer { receiver_id, amount, memo } => {
+                    let receiver_id: &AccountId = receiver_id.as_ref();
+                    self.assert_can_transfer(&caller, receiver_id);
+                    self.checkpointed(&[caller.clone(), receiver_id.clone()], |this| {
+                        this.token.internal_transfer(&caller, receiver_id, amount.0, memo.clone())
+                    });
+                    amount.0
+                }
+            };
+            amounts.push(amount.into());
+        }
+
+        let event = BatchEvent { account_id: &caller, actions: &actions, amounts };
+        events::emit("batch", &event);
+        utils::refund_deposit(deposit);
+        if promises.is_empty() {
+            return PromiseOrValue::Value(());
+        }
+        utils::join_promises(promises.into_iter()).into()
+    }
+}
@@ -0,0 +1,186 @@
+//! History of the set token balances and total supply, for snapshots taken at a past block.
+//!
+//! Every change of a balance through wrapping, unwrapping, transfers or unregistration records a
+//! checkpoint of the new balance at the current block, keeping one checkpoint per block. Voting
+//! weight read at a past block cannot be flash-borrowed. The history starts at `since`, the block
+//! the contract was initialized or migrated at; balances held then are recorded the first time
+//! they change.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, Vector};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::{env, near_bindgen, AccountId, Balance, BlockHeight};
+
+use crate::Contract;
+
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct Checkpoint {
+    block_height: BlockHeight,
+    balance: Balance,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Checkpoints {
+    since: BlockHeight,
+    counts: LookupMap<AccountId, u64>,
+    accounts: LookupMap<(AccountId, u64), Checkpoint>,
+    supply: Vector<Checkpoint>,
+}
+
+impl Checkpoints {
+    pub(crate) fn new() -> Self {
+        Self {
+            since: env::block_index(),
+            counts: LookupMap::new(b"ckc".to_vec()),
+            accounts: LookupMap::new(b"cka".to_vec()),
+            supply: Vector::new(b"cks".to_vec()),
+        }
+    }
+
+    /// The block the history starts at
+    pub(crate) fn since(&self) -> BlockHeight {
+        self.since
+    }
+
+    fn account_checkpoint(&self, account_id: &AccountId, index: u64) -> Checkpoint {
+        self.accounts.get(&(account_id.clone(), index)).unwrap()
+    }
+
+    /// Record `balance` at the current block, unless it did not change
+    fn record_account(&mut self, account_id: &AccountId, balance: Balance) {
+        let count = self.counts.get(account_id).unwrap_or(0);
+        let block_height = env::block_index();
+        let mut index = count;
+        if count > 0 {
+            let last = self.account_checkpoint(account_id, count - 1);
+            if last.balance == balance {
+                return;
+            }
+            if last.block_height == block_height {
+                index = count - 1;
+            }
+        } else if balance == 0 {
+            return;
+        }
+        self.accounts.insert(&(account_id.clone(), index), &Checkpoint { block_height, balance });
+        self.counts.insert(account_id, &(index + 1));
+    }
+
+    fn record_supply(&mut self, supply: Balance) {
+        let checkpoint = Checkpoint { block_height: env::block_index(), balance: supply };
+        match self.supply.len().checked_sub(1).map(|i| (i, self.supply.get(i).unwrap())) {
+            Some((_, last)) if last.balance == supply => {}
+            Some((i, last)) if last.block_height == checkpoint.block_height => {
+                self.supply.replace(i, &checkpoint);
+            }
+            None if supply == 0 => {}
+            _ => self.supply.push(&checkpoint),
+        }
+    }
+
+    fn assert_in_history(&self, block_height: BlockHeight) {
+        if block_height < self.since {
+            panic!("No history before block {}", self.since);
+        }
+    }
+}
+
+/// The balance of the last checkpoint at or before `block_height`, with `count` checkpoints
+/// readable through `get`
+fn search<F: Fn(u64) -> Checkpoint>(count: u64, get: F, block_height: BlockHeight) -> Balance {
+    // Number of checkpoints at or before `block_height`
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if get(mid).block_height <= block_height {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    if low == 0 {
+        0
+    } else {
+        get(low - 1).balance
+    }
+}
+
+impl Contract {
+    /// Run `f`, which may change the balances of `account_ids`, settling the rewards they earned
+    /// before and checkpointing their new balances and the total supply after
+    pub(crate) fn checkpointed<R>(
+        &mut self,
+        account_ids: &[AccountId],
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        for account_id in account_ids.iter() {
+            self.settle_rewards(account_id);
+        }
+        // The balances held since the history started
+        for account_id in account_ids.iter() {
+            if self.checkpoints.counts.get(account_id).is_none() {
+                let balance = self.token.accounts.get(account_id).unwrap_or(0);
+                if balance > 0 {
+                    self.checkpoints.accounts.insert(
+                        &(account_id.clone(), 0),
+                        &Checkpoint { block_height: self.checkpoints.since, balance },
+                    );
+                    self.checkpoints.counts.insert(account_id, &1);
+                }
+            }
+        }
+        if self.checkpoints.supply.is_empty() && self.token.total_supply > 0 {
+            let since = self.checkpoints.since;
+            let balance = self.token.total_supply;
+            self.checkpoints.supply.push(&Checkpoint { block_height: since, balance });
+        }
+
+        let result = f(self);
+
+        for account_id in account_ids.iter() {
+            let balance = self.token.accounts.get(account_id).unwrap_or(0);
+            self.checkpoints.record_account(account_id, balance);
+        }
+        self.checkpoints.record_supply(self.token.total_supply);
+        result
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_balance_of_at(
+        &self,
+        account_id: &AccountId,
+        block_height: BlockHeight,
+    ) -> Balance {
+        let checkpoints = &self.checkpoints;
+        checkpoints.assert_in_history(block_height);
+        match checkpoints.counts.get(account_id) {
+            Some(count) => {
+                search(count, |i| checkpoints.account_checkpoint(account_id, i), block_height)
+            }
+            // Unchanged since the history started
+            None => self.token.accounts.get(account_id).unwrap_or(0),
+        }
+    }
+
+    pub(crate) fn internal_total_supply_at(&self, block_height: BlockHeight) -> Balance {
+        let checkpoints = &self.checkpoints;
+        checkpoints.assert_in_history(block_height);
+        if checkpoints.supply.is_empty() {
+            return self.token.total_supply;
+        }
+        search(checkpoints.supply.len(), |i| checkpoints.supply.get(i).unwrap(), block_height)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// The set token balance of `account_id` at the end of block `block_height`
+    pub fn balance_of_at(&self, account_id: ValidAccountId, block_height: U64) -> U128 {
+        self.internal_balance_of_at(account_id.as_ref(), block_height.0).into()
+    }
+
+    /// The total supply of the set token at the end of block `block_height`
+    pub fn total_supply_at(&self, block_height: U64) -> U128 {
+        self.internal_total_supply_at(block_height.0).into()
+    }
+}
@@ -0,0 +1,177 @@
+//! Deposits of components into internal balances and their withdrawal.
+//!
+//! A component is deposited with `ft_transfer_call` on it, crediting the internal balance of the
+//! sender or of the `sender_id` named in the message. Components of a hosted set are deposited
+//! naming it as `mt_token_id`. Internal balances take storage which only registration pays for,
+//! so deposits of tokens which are not components and deposits for unregistered accounts are
+//! refused, which refunds them.
+//!
+//! Unlike `wrap`, a deposit cannot register its account: `ft_on_transfer` carries no NEAR to pay
+//! the storage with. Accounts register with `storage_deposit`, or `mt_register` for a hosted set,
+//! before depositing.
+//!
+//! A deposit for the contract's own set can also be wrapped right away by setting `wrap` in the
+//! message, optionally naming the `referrer_id` of the wrap. Only deposits crediting the sender
+//! can be wrapped, a deposit cannot mint tokens for an account which did not ask for them. If the
+//! wrap fails, so does the deposit, and the tokens are refunded.
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult,
+};
+
+use crate::external::ext_fungible_token;
+use crate::multi_token::TokenId;
+use crate::utils;
+use crate::Contract;
+
+const NO_DEPOSIT: Balance = 0;
+const ONE_YOCTO: Balance = 1;
+const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+const GAS_FOR_ON_WITHDRAWN: Gas = 10_000_000_000_000;
+
+/// The message of a deposit's `ft_transfer_call`, an empty message credits the sender
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositMsg {
+    /// The account credited, the sender if `None`
+    pub sender_id: Option<ValidAccountId>,
+    /// The hosted set the deposit is for, the contract's own set if `None`
+    pub mt_token_id: Option<TokenId>,
+    /// Wrap the internal balances of the sender once the deposit is credited, only without
+    /// `sender_id` naming another account
+    pub wrap: Option<bool>,
+    /// The referrer of the wrap, only with `wrap`
+    pub referrer_id: Option<ValidAccountId>,
+}
+
+#[ext_contract(ext_self)]
+pub trait DepositCallbacks {
+    fn on_underlying_withdrawn(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    );
+}
+
+impl Contract {
+    /// Take `amount` of `token_id` out of the internal balance of `account_id` and send it
+    pub(crate) fn withdraw_underlying(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Promise {
+        self.assert_not_swapping();
+        self.balances.subtract_balance(account_id, token_id, amount);
+        ext_fungible_token::ft_transfer(
+            account_id.clone(),
+            amount.into(),
+            None,
+            token_id,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_underlying_withdrawn(
+            account_id.clone(),
+            token_id.clone(),
+            amount.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_WITHDRAWN,
+        ))
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Credit the deposited component to the internal balance of the account in `msg`, and wrap
+    /// it if asked to
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_id = env::predecessor_account_id();
+        self.assert_not_swapping();
+        self.harvester.assert_not_harvesting(&token_id);
+        let deposit: DepositMsg = if msg.is_empty() {
+            DepositMsg::default()
+        } else {
+            serde_json::from_str(&msg).expect("Failed to parse the deposit message")
+        };
+        let sender_id: AccountId = sender_id.into();
+        let account_id: AccountId =
+            deposit.sender_id.map(|id| id.into()).unwrap_or_else(|| sender_id.clone());
+        let wrap = deposit.wrap.unwrap_or(false);
+        if wrap && account_id != sender_id {
+            panic!("Only deposits for the sender can be wrapped");
+        }
+        if deposit.referrer_id.is_some() && !wrap {
+            panic!("A referrer can only be named when the deposit is wrapped");
+        }
+        if wrap && deposit.mt_token_id.is_some() {
+            panic!("Deposits for hosted sets are wrapped with mt_wrap");
+        }
+        // Registration pays for the internal balance records, which a transfer carries no NEAR for
+        match &deposit.mt_token_id {
+            Some(mt_token_id) => self.assert_mt_depositable(mt_token_id, &account_id, &token_id),
+            None => {
+                if !self.set_info.token_ids().contains(&token_id) {
+                    panic!("{} is not a component of the set", token_id);
+                }
+                self.assert_registered(&account_id);
+            }
+        }
+        self.balances.increase_balance(&account_id, &token_id, amount.0);
+        log!("Deposited {} {} for @{}", amount.0, token_id, account_id);
+        if wrap {
+            let referrer_id: Option<AccountId> = deposit.referrer_id.map(|id| id.into());
+            self.assert_can_wrap(&account_id);
+            self.internal_wrap(&account_id, None, referrer_id.as_ref());
+        }
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Send internal balance of `token_id` to the caller, all of it if `amount` is `None`
+    #[payable]
+    pub fn withdraw(&mut self, token_id: ValidAccountId, amount: Option<U128>) -> Promise {
+        utils::assert_1_yocto();
+        let caller = env::predecessor_account_id();
+        let amount = amount
+            .map(|amount| amount.0)
+            .unwrap_or_else(|| self.balances.get_ft_balance(&caller, token_id.as_ref()));
+        if amount == 0 {
+            panic!("No {} to withdraw", token_id);
+        }
+        self.withdraw_underlying(&caller, token_id.as_ref(), amount)
+    }
+
+    /// Credit a withdrawal back to the internal balance of `account_id` if its transfer failed
+    #[private]
+    pub fn on_underlying_withdrawn(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        if !self.is_registered(&account_id) {
+            // The records of unregistered accounts are gone, the tokens stay as surplus
+            log!("Withdrawing {} failed, @{} is not registered anymore", token_id, account_id);
+            return;
+        }
+        log!("Withdrawing {} failed, crediting it back to @{}", token_id, account_id);
+        self.balances.increase_balance(&account_id, &token_id, amount.0);
+    }
+}
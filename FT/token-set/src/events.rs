@@ -0,0 +1,32 @@
+//! NEP-297 event logging for the token set contract.
+use near_sdk::serde::Serialize;
+use near_sdk::serde_json::json;
+use near_sdk::log;
+
+const EVENT_STANDARD: &str = "token-set";
+const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// Log `data` as a NEP-297 event, i.e. `EVENT_JSON:{"standard": ..., "event": ..., "data": [...]}`
+pub(crate) fn emit<T: Serialize>(event: &str, data: &T) {
+    let payload = json!({
+        "standard": EVENT_STANDARD,
+        "version": EVENT_STANDARD_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    log!("EVENT_JSON:{}", payload.to_string());
+}
+
+const NEP245_STANDARD: &str = "nep245";
+const NEP245_STANDARD_VERSION: &str = "1.0.0";
+
+/// Log a NEP-245 multi token event, with one entry of `data` per owner or transfer
+pub(crate) fn emit_nep245<T: Serialize>(event: &str, data: &[T]) {
+    let payload = json!({
+        "standard": NEP245_STANDARD,
+        "version": NEP245_STANDARD_VERSION,
+        "event": event,
+        "data": data,
+    });
+    log!("EVENT_JSON:{}", payload.to_string());
+}
@@ -0,0 +1,33 @@
+//! Interfaces of the contracts the token set calls into.
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::ext_contract;
+use near_sdk::json_types::U128;
+use near_sdk::AccountId;
+
+#[ext_contract(ext_fungible_token)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> U128;
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+}
+
+#[ext_contract(ext_non_fungible_token)]
+pub trait NonFungibleToken {
+    fn nft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: String,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    );
+}
@@ -0,0 +1,263 @@
+//! Lower mint fees for large or long-term holders.
+//!
+//! The owner sets tiers of discounts on the owner and platform fees, each starting at a minimum
+//! balance. The balance is either the caller's set token balance or its balance of a platform
+//! token, and in both cases it has to be held for `holding_blocks` so that borrowed tokens do not
+//! count. The set token balance is the lower of the current one and the one `holding_blocks` ago.
+//!
+//! The platform token lives on another contract: `refresh_fee_tier` reads the caller's balance
+//! with `ft_balance_of` and caches it. Reads at most `PLATFORM_BALANCE_VALIDITY_BLOCKS` apart
+//! form a streak, whose balance is the lowest one read. The cached balance only counts once the
+//! streak spans `holding_blocks`, and until `PLATFORM_BALANCE_VALIDITY_BLOCKS` after its last
+//! read. Tokens borrowed for a discount therefore have to be held at every read of a streak of
+//! `holding_blocks`, and a holder whose balance grew lets the streak lapse to start over.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, BlockHeight, Gas, Promise,
+    PromiseResult,
+};
+
+use crate::external::ext_fungible_token;
+use crate::rebalance::WEIGHT_DENOMINATOR;
+use crate::utils;
+use crate::Contract;
+
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_FT_BALANCE_OF: Gas = 5_000_000_000_000;
+const GAS_FOR_ON_PLATFORM_BALANCE: Gas = 10_000_000_000_000;
+/// About ten minutes
+const PLATFORM_BALANCE_VALIDITY_BLOCKS: BlockHeight = 600;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TierBasis {
+    /// The set token balance, the lower of the current one and the one `holding_blocks` ago
+    SetBalance { holding_blocks: U64 },
+    /// The lowest balance of a platform token read by `refresh_fee_tier` over at least
+    /// `holding_blocks`
+    PlatformToken { token_id: ValidAccountId, holding_blocks: U64 },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeTier {
+    /// The balance from which the tier applies
+    pub min_balance: U128,
+    /// The discount on the owner and platform fees, in basis points
+    pub discount_bps: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeTiersView {
+    pub basis: TierBasis,
+    pub tiers: Vec<FeeTier>,
+}
+
+/// The reads of an account's platform token balance, at most
+/// `PLATFORM_BALANCE_VALIDITY_BLOCKS` apart
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PlatformBalance {
+    token_id: AccountId,
+    /// The lowest balance read
+    balance: Balance,
+    /// The block of the first read
+    since: BlockHeight,
+    /// The block of the last read
+    block_height: BlockHeight,
+}
+
+impl PlatformBalance {
+    fn is_valid(&self) -> bool {
+        env::block_index() <= self.block_height + PLATFORM_BALANCE_VALIDITY_BLOCKS
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FeeTiers {
+    basis: TierBasis,
+    /// Sorted by increasing minimum balance
+    tiers: Vec<FeeTier>,
+    platform_balances: LookupMap<AccountId, PlatformBalance>,
+}
+
+impl FeeTiers {
+    pub(crate) fn new() -> Self {
+        Self {
+            basis: TierBasis::SetBalance { holding_blocks: 0.into() },
+            tiers: vec![],
+            platform_balances: LookupMap::new(b"ftc".to_vec()),
+        }
+    }
+
+    /// The discount of the highest tier `balance` reaches
+    fn discount_for(&self, balance: Balance) -> u32 {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|tier| balance >= tier.min_balance.0)
+            .map_or(0, |tier| tier.discount_bps)
+    }
+}
+
+#[ext_contract(ext_self)]
+pub trait FeeTierCallbacks {
+    fn on_platform_balance(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        deposit: U128,
+    ) -> u32;
+}
+
+impl Contract {
+    /// The balance the tier of `account_id` is chosen by
+    fn tier_balance(&self, account_id: &AccountId) -> Balance {
+        match &self.fee_tiers.basis {
+            TierBasis::SetBalance { holding_blocks } => {
+                let balance = self.token.accounts.get(account_id).unwrap_or(0);
+                let block_height = std::cmp::max(
+                    env::block_index().saturating_sub(holding_blocks.0),
+                    self.checkpoints.since(),
+                );
+                std::cmp::min(balance, self.internal_balance_of_at(account_id, block_height))
+            }
+            TierBasis::PlatformToken { token_id, holding_blocks } => {
+                match self.fee_tiers.platform_balances.get(account_id) {
+                    Some(cached)
+                        if &cached.token_id == token_id.as_ref()
+                            && cached.is_valid()
+                            && cached.block_height >= cached.since + holding_blocks.0 =>
+                    {
+                        cached.balance
+                    }
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    /// The discount on the mint fees of `account_id`, in basis points
+    pub(crate) fn fee_discount(&self, account_id: &AccountId) -> u32 {
+        if self.fee_tiers.tiers.is_empty() {
+            return 0;
+        }
+        self.fee_tiers.discount_for(self.tier_balance(account_id))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Replace the fee tiers, which have to be sorted by increasing minimum balance with
+    /// increasing discounts. No tiers means no discounts.
+    #[payable]
+    pub fn set_fee_tiers(&mut self, basis: TierBasis, tiers: Vec<FeeTier>) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        for tier in tiers.iter() {
+            if tier.discount_bps > WEIGHT_DENOMINATOR {
+                panic!("Expected the discounts to be at most {}", WEIGHT_DENOMINATOR);
+            }
+        }
+        for pair in tiers.windows(2) {
+            if pair[0].min_balance.0 >= pair[1].min_balance.0
+                || pair[0].discount_bps > pair[1].discount_bps
+            {
+                panic!("Expected the tiers to be sorted by increasing balance and discount");
+            }
+        }
+        self.fee_tiers.basis = basis;
+        self.fee_tiers.tiers = tiers;
+    }
+
+    pub fn get_fee_tiers(&self) -> FeeTiersView {
+        FeeTiersView { basis: self.fee_tiers.basis.clone(), tiers: self.fee_tiers.tiers.clone() }
+    }
+
+    /// The discount `account_id` would get on the mint fees now, in basis points
+    pub fn get_fee_discount(&self, account_id: ValidAccountId) -> u32 {
+        self.fee_discount(account_id.as_ref())
+    }
+
+    /// Read the caller's balance of the platform token the tiers are based on, extending its
+    /// streak of reads if the last one is recent enough. The caller pays for caching it from the
+    /// attached deposit.
+    #[payable]
+    pub fn refresh_fee_tier(&mut self) -> Promise {
+        utils::assert_at_least_1_yocto();
+        let token_id: AccountId = match &self.fee_tiers.basis {
+            TierBasis::PlatformToken { token_id, .. } => token_id.clone().into(),
+            TierBasis::SetBalance { .. } => {
+                panic!("The fee tiers are not based on a platform token")
+            }
+        };
+        let account_id = env::predecessor_account_id();
+        ext_fungible_token::ft_balance_of(
+            account_id.clone(),
+            &token_id,
+            NO_DEPOSIT,
+            GAS_FOR_FT_BALANCE_OF,
+        )
+        .then(ext_self::on_platform_balance(
+            account_id,
+            token_id,
+            env::attached_deposit().into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_PLATFORM_BALANCE,
+        ))
+    }
+
+    /// Cache the platform token balance, refunding what the deposit did not use. The deposit
+    /// is refunded whole if it does not cover the storage or the balance could not be read, which
+    /// leaves the streak of reads as it was.
+    ///
+    /// return the discount of the account
+    #[private]
+    pub fn on_platform_balance(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        deposit: U128,
+    ) -> u32 {
+        let balance = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).ok(),
+            _ => None,
+        };
+        let balance = match balance {
+            Some(balance) => balance.0,
+            None => {
+                log!("Could not read the balance of {} of @{}", token_id, account_id);
+                Promise::new(account_id).transfer(deposit.0);
+                return 0;
+            }
+        };
+        let initial_storage_usage = env::storage_usage();
+        let block_height = env::block_index();
+        let cached = match self.fee_tiers.platform_balances.get(&account_id) {
+            Some(streak) if streak.token_id == token_id && streak.is_valid() => PlatformBalance {
+                balance: std::cmp::min(streak.balance, balance),
+                block_height,
+                ..streak
+            },
+            _ => PlatformBalance { token_id, balance, since: block_height, block_height },
+        };
+        self.fee_tiers.platform_balances.insert(&account_id, &cached);
+        let cost = env::storage_usage().saturating_sub(initial_storage_usage) as Balance
+            * env::storage_byte_cost();
+        if cost > deposit.0 {
+            log!("{} yoctoNEAR have to be attached to cache the balance", cost);
+            self.fee_tiers.platform_balances.remove(&account_id);
+            Promise::new(account_id).transfer(deposit.0);
+            return 0;
+        }
+        if deposit.0 > cost {
+            Promise::new(account_id.clone()).transfer(deposit.0 - cost);
+        }
+        self.fee_discount(&account_id)
+    }
+}
@@ -0,0 +1,71 @@
+//! The NEP-141 core of the set token.
+//!
+//! Written out rather than generated by `impl_fungible_token_core!` so that transfers go through
+//! the set's own checks before reaching `FungibleToken`.
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{env, near_bindgen, AccountId, PromiseOrValue};
+
+use crate::Contract;
+
+impl Contract {
+    /// Called before any set tokens move from the caller to `receiver_id`
+    fn before_transfer(&self, receiver_id: &AccountId) -> [AccountId; 2] {
+        let sender_id = env::predecessor_account_id();
+        self.assert_can_transfer(&sender_id, receiver_id);
+        [sender_id, receiver_id.clone()]
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
+        let account_ids = self.before_transfer(receiver_id.as_ref());
+        self.checkpointed(&account_ids, |this| this.token.ft_transfer(receiver_id, amount, memo))
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let account_ids = self.before_transfer(receiver_id.as_ref());
+        self.checkpointed(&account_ids, |this| {
+            this.token.ft_transfer_call(receiver_id, amount, memo, msg)
+        })
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        receiver_id: ValidAccountId,
+        amount: U128,
+    ) -> U128 {
+        let sender_id: AccountId = sender_id.into();
+        let account_ids = [sender_id.clone(), receiver_id.to_string()];
+        let (used_amount, burned_amount) = self.checkpointed(&account_ids, |this| {
+            this.token.internal_ft_resolve_transfer(&sender_id, receiver_id, amount)
+        });
+        if burned_amount > 0 {
+            self.on_tokens_burned(sender_id, burned_amount);
+        }
+        used_amount.into()
+    }
+}
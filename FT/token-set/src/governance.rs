@@ -0,0 +1,370 @@
+//! Proposals voted on by the set token holders.
+//!
+//! Holders propose changes of the composition, the fees, the supply caps or the referral cap.
+//! Votes are weighted by the voter's balance at the block before the proposal was made, so tokens
+//! bought or borrowed for the vote do not count. Once the voting period ended, a proposal which
+//! reached the quorum and has more votes for than against can be executed by anyone. Execution
+//! goes through the same validation as the owner's methods and the set's init.
+//!
+//! A set without supply holds no reserves, so a `SetRatios` proposal simply replaces its
+//! components. Once set tokens exist, only the ratios of the current components can change and
+//! the change goes through the rebalance path: executing the proposal fetches the oracle's prices
+//! and leaves a pending rebalance towards the proposed ratios, whose reserves are swapped with
+//! `execute_rebalance` before the ratios are committed. The proposed ratios have to keep the
+//! value of a set token at those prices, within the rebalancer's `max_slippage_bps`, and the
+//! proposal only counts as executed once its rebalance committed. A cancelled rebalance leaves
+//! the proposal passed, to be executed again.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, Vector};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, BlockHeight, Gas, PromiseOrValue,
+};
+
+use crate::allowlist::ComponentsStatus;
+use crate::rebalance::{self, WEIGHT_DENOMINATOR};
+use crate::supply_caps::SupplyCaps;
+use crate::utils::{self, U256};
+use crate::{events, Contract, FeeReceiver, TokenWithRatioValid};
+
+const DEFAULT_QUORUM_BPS: u32 = 2_000;
+/// About a day
+const DEFAULT_VOTING_PERIOD_BLOCKS: BlockHeight = 86_400;
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_ON_PROPOSAL_PRICES: Gas = 30_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ProposalKind {
+    /// Replace the tokens and their ratios. Once set tokens exist, only the ratios of the current
+    /// tokens can change, through a rebalance.
+    SetRatios { set_ratios: Vec<TokenWithRatioValid> },
+    /// Change the weights the set is rebalanced towards
+    TargetWeights { target_weights: Vec<u32> },
+    /// Change the fees, which still go to the current owner and platform
+    Fees { owner_fee: U128, platform_fee: U128 },
+    /// Replace both supply caps, the only way to raise or lift them
+    SupplyCaps { max_total_supply: Option<U128>, max_account_mint: Option<U128> },
+    /// Change the cap on the share of the owner fee going to referrers, in basis points
+    ReferralCap { max_rate_bps: u32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ProposalStatus {
+    Active,
+    Passed,
+    Rejected,
+    Executed,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Proposal {
+    pub proposer: AccountId,
+    pub description: String,
+    pub kind: ProposalKind,
+    /// The block the voting weights are read at
+    pub snapshot_block: U64,
+    /// The last block votes are accepted in
+    pub end_block: U64,
+    /// The votes needed for the proposal to be valid, for and against together
+    pub quorum: U128,
+    pub votes_for: U128,
+    pub votes_against: U128,
+    pub executed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ProposalView {
+    pub id: u64,
+    pub status: ProposalStatus,
+    #[serde(flatten)]
+    pub proposal: Proposal,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GovernanceConfig {
+    /// The share of the supply which has to vote, in basis points
+    pub quorum_bps: u32,
+    pub voting_period_blocks: U64,
+}
+
+#[ext_contract(ext_self)]
+pub trait GovernanceCallbacks {
+    fn on_proposal_prices(&mut self, proposal_id: u64, target_ratios: Vec<u32>);
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Governance {
+    pub(crate) quorum_bps: u32,
+    pub(crate) voting_period_blocks: BlockHeight,
+    pub(crate) proposals: Vector<Proposal>,
+    pub(crate) votes: LookupMap<(u64, AccountId), bool>,
+}
+
+impl Governance {
+    pub(crate) fn new() -> Self {
+        Self {
+            quorum_bps: DEFAULT_QUORUM_BPS,
+            voting_period_blocks: DEFAULT_VOTING_PERIOD_BLOCKS,
+            proposals: Vector::new(b"gov-p".to_vec()),
+            votes: LookupMap::new(b"gov-v".to_vec()),
+        }
+    }
+
+    fn get_proposal(&self, proposal_id: u64) -> Proposal {
+        self.proposals
+            .get(proposal_id)
+            .unwrap_or_else(|| panic!("No proposal with id {}", proposal_id))
+    }
+}
+
+impl Proposal {
+    fn status(&self) -> ProposalStatus {
+        if self.executed {
+            ProposalStatus::Executed
+        } else if env::block_index() <= self.end_block.0 {
+            ProposalStatus::Active
+        } else if self.votes_for.0 + self.votes_against.0 >= self.quorum.0
+            && self.votes_for.0 > self.votes_against.0
+        {
+            ProposalStatus::Passed
+        } else {
+            ProposalStatus::Rejected
+        }
+    }
+}
+
+impl Contract {
+    fn voting_weight(&self, account_id: &AccountId, proposal: &Proposal) -> Balance {
+        self.internal_balance_of_at(account_id, proposal.snapshot_block.0)
+    }
+
+    fn has_supply(&self) -> bool {
+        self.token.total_supply > 0 || self.unclaimed_supply() > 0
+    }
+
+    /// The proposed ratios in the order of the set's current components, which they have to
+    /// keep while set tokens exist
+    fn proposed_ratios(&self, set_ratios: &[TokenWithRatioValid]) -> Vec<u32> {
+        let token_ids = self.set_info.token_ids();
+        if set_ratios.len() != token_ids.len() {
+            panic!("While set tokens exist, only the ratios of the current components can change");
+        }
+        token_ids
+            .iter()
+            .map(|token_id| {
+                let ratio = set_ratios
+                    .iter()
+                    .find(|ratio| ratio.token_id.as_ref() == token_id)
+                    .unwrap_or_else(|| {
+                        panic!("While set tokens exist, {} has to stay a component", token_id)
+                    })
+                    .ratio;
+                if ratio == 0 {
+                    panic!("Expected every ratio to be positive");
+                }
+                ratio
+            })
+            .collect()
+    }
+
+    /// Mark a `SetRatios` proposal executed once its rebalance committed. The old target weights
+    /// would pull the set back to its previous composition.
+    pub(crate) fn mark_proposal_executed(&mut self, proposal_id: u64) {
+        let mut proposal = self.governance.get_proposal(proposal_id);
+        proposal.executed = true;
+        self.governance.proposals.replace(proposal_id, &proposal);
+        self.rebalancer.target_weights = vec![];
+        events::emit("proposal_executed", &proposal_id);
+    }
+
+    fn apply_proposal(&mut self, kind: ProposalKind) {
+        match kind {
+            ProposalKind::SetRatios { set_ratios } => {
+                self.rebalancer.assert_not_pending();
+                self.set_info.set_ratios(set_ratios);
+                self.rebalancer.target_weights = vec![];
+                // The new components have to be verified and their symbols fetched
+                self.verification.status = ComponentsStatus::Pending;
+                self.icon.symbols = vec![];
+                self.refresh_icon();
+                self.fetch_component_metadata();
+            }
+            ProposalKind::TargetWeights { target_weights } => {
+                self.internal_set_target_weights(target_weights);
+            }
+            ProposalKind::Fees { owner_fee, platform_fee } => {
+                let platform_id = self.set_info.fee.platform_id.clone();
+                self.set_info.set_fee(FeeReceiver {
+                    owner_fee: owner_fee.0,
+                    platform_fee: platform_fee.0,
+                    platform_id,
+                });
+            }
+            ProposalKind::SupplyCaps { max_total_supply, max_account_mint } => {
+                self.set_info.caps = SupplyCaps::from((max_total_supply, max_account_mint));
+            }
+            ProposalKind::ReferralCap { max_rate_bps } => {
+                self.referrals.set_max_rate(max_rate_bps);
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn set_governance_config(&mut self, config: GovernanceConfig) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if config.quorum_bps > WEIGHT_DENOMINATOR {
+            panic!("Expected the quorum to be at most {}", WEIGHT_DENOMINATOR);
+        }
+        self.governance.quorum_bps = config.quorum_bps;
+        self.governance.voting_period_blocks = config.voting_period_blocks.0;
+    }
+
+    pub fn get_governance_config(&self) -> GovernanceConfig {
+        GovernanceConfig {
+            quorum_bps: self.governance.quorum_bps,
+            voting_period_blocks: self.governance.voting_period_blocks.into(),
+        }
+    }
+
+    /// Propose a change, paying for the proposal's storage with the attached deposit. Only
+    /// accounts which held set tokens at the previous block can propose.
+    ///
+    /// return the id of the proposal
+    #[payable]
+    pub fn propose(&mut self, kind: ProposalKind, description: String) -> u64 {
+        let initial_storage_usage = env::storage_usage();
+        let proposer = env::predecessor_account_id();
+        let snapshot_block = env::block_index() - 1;
+        if self.internal_balance_of_at(&proposer, snapshot_block) == 0 {
+            panic!("Only holders of the set token can propose");
+        }
+        let supply = self.internal_total_supply_at(snapshot_block);
+        let quorum = (U256::from(supply) * U256::from(self.governance.quorum_bps)
+            / U256::from(WEIGHT_DENOMINATOR))
+        .as_u128();
+        let proposal = Proposal {
+            proposer,
+            description,
+            kind,
+            snapshot_block: snapshot_block.into(),
+            end_block: (env::block_index() + self.governance.voting_period_blocks).into(),
+            quorum: quorum.into(),
+            votes_for: 0.into(),
+            votes_against: 0.into(),
+            executed: false,
+        };
+        let id = self.governance.proposals.len();
+        self.governance.proposals.push(&proposal);
+        let status = ProposalStatus::Active;
+        events::emit("proposal_created", &ProposalView { id, status, proposal });
+        utils::charge_storage(initial_storage_usage);
+        id
+    }
+
+    /// Vote for or against an active proposal, paying for the vote's storage with the attached
+    /// deposit
+    #[payable]
+    pub fn vote(&mut self, proposal_id: u64, approve: bool) -> U128 {
+        let initial_storage_usage = env::storage_usage();
+        let voter = env::predecessor_account_id();
+        let mut proposal = self.governance.get_proposal(proposal_id);
+        if proposal.status() != ProposalStatus::Active {
+            panic!("The proposal is not open for voting");
+        }
+        if self.governance.votes.insert(&(proposal_id, voter.clone()), &approve).is_some() {
+            panic!("{} already voted", voter);
+        }
+        let weight = self.voting_weight(&voter, &proposal);
+        if weight == 0 {
+            panic!("{} held no set tokens at block {}", voter, proposal.snapshot_block.0);
+        }
+        if approve {
+            proposal.votes_for = (proposal.votes_for.0 + weight).into();
+        } else {
+            proposal.votes_against = (proposal.votes_against.0 + weight).into();
+        }
+        self.governance.proposals.replace(proposal_id, &proposal);
+        utils::charge_storage(initial_storage_usage);
+        weight.into()
+    }
+
+    /// Apply a passed proposal, anyone can call this once its voting period ended. New ratios
+    /// for a set with supply are executed once the oracle's prices are known, see
+    /// `on_proposal_prices`.
+    pub fn execute_proposal(&mut self, proposal_id: u64) -> PromiseOrValue<()> {
+        let mut proposal = self.governance.get_proposal(proposal_id);
+        let status = proposal.status();
+        if status != ProposalStatus::Passed {
+            panic!("Only passed proposals can be executed, the proposal is {:?}", status);
+        }
+        if let ProposalKind::SetRatios { set_ratios } = &proposal.kind {
+            if self.has_supply() {
+                self.rebalancer.assert_not_pending();
+                let target_ratios = self.proposed_ratios(set_ratios);
+                return self
+                    .fetch_prices()
+                    .then(ext_self::on_proposal_prices(
+                        proposal_id,
+                        target_ratios,
+                        &env::current_account_id(),
+                        NO_DEPOSIT,
+                        GAS_FOR_ON_PROPOSAL_PRICES,
+                    ))
+                    .into();
+            }
+        }
+        proposal.executed = true;
+        self.governance.proposals.replace(proposal_id, &proposal);
+        self.apply_proposal(proposal.kind);
+        events::emit("proposal_executed", &proposal_id);
+        PromiseOrValue::Value(())
+    }
+
+    /// Start rebalancing towards the ratios of a passed `SetRatios` proposal at the oracle's
+    /// prices. The proposal stays passed, and can be executed again, until the rebalance
+    /// commits.
+    #[private]
+    pub fn on_proposal_prices(&mut self, proposal_id: u64, target_ratios: Vec<u32>) {
+        let proposal = self.governance.get_proposal(proposal_id);
+        if proposal.status() != ProposalStatus::Passed {
+            panic!("The proposal was executed already");
+        }
+        // Another rebalance may have started while the prices were fetched
+        self.rebalancer.assert_not_pending();
+        let prices = self.received_prices();
+        let ratios: Vec<u32> = self.set_info.get_ratios().iter().map(|r| r.ratio).collect();
+        rebalance::assert_value_kept(
+            &ratios,
+            &target_ratios,
+            &prices,
+            self.rebalancer.max_slippage_bps,
+        );
+        self.start_rebalance(&target_ratios, &prices, Some(proposal_id));
+    }
+
+    pub fn get_proposal(&self, proposal_id: u64) -> Option<ProposalView> {
+        self.governance
+            .proposals
+            .get(proposal_id)
+            .map(|proposal| ProposalView { id: proposal_id, status: proposal.status(), proposal })
+    }
+
+    pub fn get_proposals(&self, from_index: u64, limit: u64) -> Vec<ProposalView> {
+        let to_index = std::cmp::min(from_index + limit, self.governance.proposals.len());
+        (from_index..to_index).filter_map(|id| self.get_proposal(id)).collect()
+    }
+
+    pub fn get_vote(&self, proposal_id: u64, account_id: ValidAccountId) -> Option<bool> {
+        self.governance.votes.get(&(proposal_id, account_id.into()))
+    }
+}
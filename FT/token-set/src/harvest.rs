@@ -0,0 +1,400 @@
+//! Harvesting the rewards which yield-bearing components accrue to the set contract.
+//!
+//! Each yield source names a component, the method claiming its rewards and the token the
+//! rewards are paid in. Harvesting a source calls the claim method between two reads of the set's
+//! balance of the reward token, and the difference is the harvested amount. Deposits landing
+//! during a harvest would be counted as rewards, so deposits of the sources' reward tokens are
+//! refused until the harvest completes.
+//!
+//! Only one harvest runs at a time. Should a harvest's callbacks fail, e.g. run out of gas, the
+//! owner ends it with `cancel_harvest`.
+//!
+//! Rewards are either reinvested, raising the reward token's ratio so that every set token is
+//! backed by more of it, or distributed: a rewards-per-token accumulator is raised and holders
+//! claim their share into their internal balances.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult,
+};
+
+use crate::external::ext_fungible_token;
+use crate::utils::{self, U256};
+use crate::{events, Contract};
+
+const NO_DEPOSIT: Balance = 0;
+/// Scale of the rewards per set token
+const REWARD_PRECISION: u128 = 1_000_000_000_000;
+const GAS_FOR_FT_BALANCE_OF: Gas = 5_000_000_000_000;
+const GAS_FOR_CLAIM: Gas = 50_000_000_000_000;
+const GAS_FOR_ON_HARVEST_CLAIMED: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_HARVEST_BALANCE_BEFORE: Gas =
+    GAS_FOR_CLAIM + GAS_FOR_FT_BALANCE_OF + GAS_FOR_ON_HARVEST_CLAIMED + 20_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum HarvestMode {
+    /// Raise the backing per set token, the reward token has to be a component
+    Reinvest,
+    /// Let holders claim the rewards pro-rata to their balances
+    Distribute,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct YieldSource {
+    /// The component accruing the rewards, which the claim method is called on
+    pub component_id: AccountId,
+    pub claim_method: String,
+    /// JSON arguments of the claim method
+    pub claim_args: String,
+    pub claim_deposit: U128,
+    pub reward_token_id: AccountId,
+    pub mode: HarvestMode,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct HolderRewards {
+    /// The rewards per token already accounted for
+    paid_per_token: u128,
+    accrued: Balance,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Harvester {
+    pub(crate) sources: Vec<YieldSource>,
+    pub(crate) harvesting: bool,
+    /// Rewards per set token for each distributed reward token, scaled by `REWARD_PRECISION`
+    reward_per_token: LookupMap<AccountId, u128>,
+    holders: LookupMap<(AccountId, AccountId), HolderRewards>,
+    /// Harvested rewards not yet reinvested or distributed, e.g. while nothing is wrapped
+    carry: LookupMap<AccountId, Balance>,
+    /// Distributed rewards not yet claimed
+    unclaimed: LookupMap<AccountId, Balance>,
+}
+
+impl Harvester {
+    pub(crate) fn new() -> Self {
+        Self {
+            sources: vec![],
+            harvesting: false,
+            reward_per_token: LookupMap::new(b"rpt".to_vec()),
+            holders: LookupMap::new(b"rwh".to_vec()),
+            carry: LookupMap::new(b"rwc".to_vec()),
+            unclaimed: LookupMap::new(b"rwu".to_vec()),
+        }
+    }
+
+    /// The distributed rewards of `token_id` the set holds for its holders. The carry of
+    /// reinvested rewards is part of the reserves already.
+    pub(crate) fn held_rewards(&self, token_id: &AccountId) -> Balance {
+        if !self.distributed_tokens().contains(token_id) {
+            return 0;
+        }
+        self.carry.get(token_id).unwrap_or(0) + self.unclaimed.get(token_id).unwrap_or(0)
+    }
+
+    /// Refuse deposits of `token_id` while a harvest is counting them as rewards
+    pub(crate) fn assert_not_harvesting(&self, token_id: &AccountId) {
+        if self.harvesting && self.sources.iter().any(|s| &s.reward_token_id == token_id) {
+            panic!("Deposits of {} are refused during a harvest", token_id);
+        }
+    }
+
+    fn distributed_tokens(&self) -> Vec<AccountId> {
+        let mut token_ids: Vec<AccountId> = vec![];
+        for source in self.sources.iter() {
+            if source.mode == HarvestMode::Distribute
+                && !token_ids.contains(&source.reward_token_id)
+            {
+                token_ids.push(source.reward_token_id.clone());
+            }
+        }
+        token_ids
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct HarvestEvent<'a> {
+    component_id: &'a AccountId,
+    reward_token_id: &'a AccountId,
+    amount: U128,
+    mode: HarvestMode,
+}
+
+#[ext_contract(ext_self)]
+pub trait HarvestCallbacks {
+    fn on_harvest_balance_before(&mut self, component_id: AccountId) -> PromiseOrValue<U128>;
+    fn on_harvest_claimed(&mut self, component_id: AccountId, balance_before: U128) -> U128;
+}
+
+fn promise_balance() -> Option<Balance> {
+    match env::promise_result(0) {
+        PromiseResult::Successful(value) => {
+            serde_json::from_slice::<U128>(&value).ok().map(|balance| balance.0)
+        }
+        _ => None,
+    }
+}
+
+impl Contract {
+    /// Account for the rewards `account_id` earned with its current balance. Called before the
+    /// balance changes.
+    pub(crate) fn settle_rewards(&mut self, account_id: &AccountId) {
+        let balance = self.token.accounts.get(account_id).unwrap_or(0);
+        for token_id in self.harvester.distributed_tokens() {
+            let reward_per_token = self.harvester.reward_per_token.get(&token_id).unwrap_or(0);
+            let key = (account_id.clone(), token_id);
+            let mut holder = self.harvester.holders.get(&key).unwrap_or_default();
+            if holder.paid_per_token == reward_per_token {
+                continue;
+            }
+            holder.accrued += (U256::from(balance)
+                * U256::from(reward_per_token - holder.paid_per_token)
+                / U256::from(REWARD_PRECISION))
+            .as_u128();
+            holder.paid_per_token = reward_per_token;
+            self.harvester.holders.insert(&key, &holder);
+        }
+    }
+
+    fn source_index(&self, component_id: &AccountId) -> usize {
+        self.harvester
+            .sources
+            .iter()
+            .position(|source| &source.component_id == component_id)
+            .unwrap_or_else(|| panic!("{} is not a yield source", component_id))
+    }
+
+    pub(crate) fn reinvest(&mut self, token_id: &AccountId, amount: Balance) {
+        self.set_info.add_reserve(token_id, amount);
+        let carry = self.harvester.carry.get(token_id).unwrap_or(0) + amount;
+        // Escrowed set tokens and referral rewards are backed as well
+        let supply = self.token.total_supply + self.unclaimed_supply();
+        let mut ratios: Vec<u32> = self.set_info.get_ratios().iter().map(|r| r.ratio).collect();
+        let i = self.set_info.token_ids().iter().position(|id| id == token_id).unwrap();
+        // What would overflow the ratio stays in the carry, as a harvest cannot fail once claimed
+        let increase = if supply == 0 { 0 } else { carry / supply };
+        let increase = increase.min((u32::MAX - ratios[i]) as u128);
+        if increase > 0 {
+            ratios[i] += increase as u32;
+            self.set_info.update_ratios(&ratios);
+            self.refresh_icon();
+        }
+        self.harvester.carry.insert(token_id, &(carry - increase * supply));
+    }
+
+    pub(crate) fn distribute(&mut self, token_id: &AccountId, amount: Balance) {
+        let carry = self.harvester.carry.get(token_id).unwrap_or(0) + amount;
+        let supply = self.token.total_supply;
+        if supply == 0 {
+            self.harvester.carry.insert(token_id, &carry);
+            return;
+        }
+        let increase =
+            (U256::from(carry) * U256::from(REWARD_PRECISION) / U256::from(supply)).as_u128();
+        let reward_per_token = self.harvester.reward_per_token.get(token_id).unwrap_or(0);
+        self.harvester.reward_per_token.insert(token_id, &(reward_per_token + increase));
+        // What rounding kept from the holders
+        let distributed =
+            (U256::from(increase) * U256::from(supply) / U256::from(REWARD_PRECISION)).as_u128();
+        self.harvester.carry.insert(token_id, &(carry - distributed));
+        let unclaimed = self.harvester.unclaimed.get(token_id).unwrap_or(0);
+        self.harvester.unclaimed.insert(token_id, &(unclaimed + distributed));
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Add or replace the yield source of `component_id`
+    #[payable]
+    pub fn set_yield_source(&mut self, source: YieldSource) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if self.harvester.harvesting {
+            panic!("A harvest is in progress");
+        }
+        let token_ids = self.set_info.token_ids();
+        if !token_ids.contains(&source.component_id) {
+            panic!("{} is not in the set", source.component_id);
+        }
+        if source.mode == HarvestMode::Reinvest && !token_ids.contains(&source.reward_token_id) {
+            panic!("Only rewards paid in a component can be reinvested");
+        }
+        match self.harvester.sources.iter().position(|s| s.component_id == source.component_id) {
+            Some(i) => {
+                let old = &self.harvester.sources[i];
+                if old.mode == HarvestMode::Distribute
+                    && (source.mode != HarvestMode::Distribute
+                        || source.reward_token_id != old.reward_token_id)
+                {
+                    panic!("Distributed rewards stay claimable, their source cannot be changed");
+                }
+                self.harvester.sources[i] = source;
+            }
+            None => self.harvester.sources.push(source),
+        }
+    }
+
+    #[payable]
+    pub fn remove_yield_source(&mut self, component_id: ValidAccountId) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if self.harvester.harvesting {
+            panic!("A harvest is in progress");
+        }
+        let i = self.source_index(component_id.as_ref());
+        if self.harvester.sources[i].mode == HarvestMode::Distribute {
+            panic!("Distributed rewards stay claimable, their source cannot be removed");
+        }
+        self.harvester.sources.remove(i);
+    }
+
+    /// End a harvest whose callbacks failed. Whatever it claimed stays as surplus, for `skim`.
+    #[payable]
+    pub fn cancel_harvest(&mut self) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if !self.harvester.harvesting {
+            panic!("No harvest is in progress");
+        }
+        self.harvester.harvesting = false;
+        log!("Cancelled the harvest");
+    }
+
+    /// Claim the rewards of a yield source and reinvest or distribute them. Anyone can call this.
+    pub fn harvest(&mut self, component_id: ValidAccountId) -> Promise {
+        if self.harvester.harvesting {
+            panic!("A harvest is in progress");
+        }
+        self.rebalancer.assert_not_pending();
+        let i = self.source_index(component_id.as_ref());
+        let reward_token_id = self.harvester.sources[i].reward_token_id.clone();
+        self.harvester.harvesting = true;
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            &reward_token_id,
+            NO_DEPOSIT,
+            GAS_FOR_FT_BALANCE_OF,
+        )
+        .then(ext_self::on_harvest_balance_before(
+            component_id.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_HARVEST_BALANCE_BEFORE,
+        ))
+    }
+
+    #[private]
+    pub fn on_harvest_balance_before(&mut self, component_id: AccountId) -> PromiseOrValue<U128> {
+        let balance_before = match promise_balance() {
+            Some(balance) => balance,
+            None => {
+                log!("Could not read the balance of the reward token");
+                self.harvester.harvesting = false;
+                return PromiseOrValue::Value(0.into());
+            }
+        };
+        let source = self.harvester.sources[self.source_index(&component_id)].clone();
+        Promise::new(component_id.clone())
+            .function_call(
+                source.claim_method.into_bytes(),
+                source.claim_args.into_bytes(),
+                source.claim_deposit.0,
+                GAS_FOR_CLAIM,
+            )
+            .then(ext_fungible_token::ft_balance_of(
+                env::current_account_id(),
+                &source.reward_token_id,
+                NO_DEPOSIT,
+                GAS_FOR_FT_BALANCE_OF,
+            ))
+            .then(ext_self::on_harvest_claimed(
+                component_id,
+                balance_before.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_ON_HARVEST_CLAIMED,
+            ))
+            .into()
+    }
+
+    /// return the amount harvested
+    #[private]
+    pub fn on_harvest_claimed(&mut self, component_id: AccountId, balance_before: U128) -> U128 {
+        self.harvester.harvesting = false;
+        let amount = match promise_balance() {
+            Some(balance) => balance.saturating_sub(balance_before.0),
+            None => 0,
+        };
+        if amount == 0 {
+            return 0.into();
+        }
+        let source = self.harvester.sources[self.source_index(&component_id)].clone();
+        match source.mode {
+            HarvestMode::Reinvest => self.reinvest(&source.reward_token_id, amount),
+            HarvestMode::Distribute => self.distribute(&source.reward_token_id, amount),
+        }
+        events::emit(
+            "harvest",
+            &HarvestEvent {
+                component_id: &component_id,
+                reward_token_id: &source.reward_token_id,
+                amount: amount.into(),
+                mode: source.mode,
+            },
+        );
+        amount.into()
+    }
+
+    /// Credit the caller's distributed rewards to its internal balance, from which they can be
+    /// withdrawn
+    ///
+    /// return the amount credited
+    pub fn claim_rewards(&mut self, reward_token_id: ValidAccountId) -> U128 {
+        let caller = env::predecessor_account_id();
+        self.assert_registered(&caller);
+        self.settle_rewards(&caller);
+        let key = (caller.clone(), reward_token_id.to_string());
+        let mut holder = match self.harvester.holders.get(&key) {
+            Some(holder) => holder,
+            None => return 0.into(),
+        };
+        let amount = holder.accrued;
+        holder.accrued = 0;
+        self.harvester.holders.insert(&key, &holder);
+        let unclaimed = self.harvester.unclaimed.get(&key.1).unwrap();
+        self.harvester.unclaimed.insert(&key.1, &(unclaimed - amount));
+        self.balances.increase_balance(&caller, reward_token_id.as_ref(), amount);
+        amount.into()
+    }
+
+    pub fn get_claimable_rewards(
+        &self,
+        account_id: ValidAccountId,
+        reward_token_id: ValidAccountId,
+    ) -> U128 {
+        let balance = self.token.accounts.get(account_id.as_ref()).unwrap_or(0);
+        let reward_per_token =
+            self.harvester.reward_per_token.get(reward_token_id.as_ref()).unwrap_or(0);
+        let holder = self
+            .harvester
+            .holders
+            .get(&(account_id.into(), reward_token_id.into()))
+            .unwrap_or_default();
+        let pending = (U256::from(balance)
+            * U256::from(reward_per_token - holder.paid_per_token)
+            / U256::from(REWARD_PRECISION))
+        .as_u128();
+        (holder.accrued + pending).into()
+    }
+
+    pub fn get_yield_sources(&self) -> Vec<YieldSource> {
+        self.harvester.sources.clone()
+    }
+}
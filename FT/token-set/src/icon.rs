@@ -0,0 +1,164 @@
+//! Icon and description of the set generated from its components.
+//!
+//! The icon is a disc split into one band per component, each sized by its ratio and colored from
+//! a hash of the token's account id, with the components' symbols on top. The symbols are fetched
+//! from the components' `ft_metadata`, while the bands are redrawn from the cached symbols
+//! whenever the composition changes.
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde_json;
+use near_sdk::{env, ext_contract, near_bindgen, Balance, Gas, Promise, PromiseResult};
+
+use crate::external::ext_fungible_token;
+use crate::utils;
+use crate::{Contract, TokenWithRatio};
+
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_FT_METADATA: Gas = 5_000_000_000_000;
+const GAS_FOR_ON_COMPONENT_METADATA: Gas = 20_000_000_000_000;
+/// At most this many symbols are written on the icon
+const MAX_ICON_SYMBOLS: usize = 3;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct SetIcon {
+    /// Whether the metadata icon is generated, i.e. no icon was given
+    pub(crate) auto: bool,
+    /// The components' symbols, in the order of the set's ratios
+    pub(crate) symbols: Vec<String>,
+}
+
+#[ext_contract(ext_self)]
+pub trait IconCallbacks {
+    fn on_component_metadata(&mut self);
+}
+
+impl Contract {
+    /// Fetch the metadata of every component, which also probes that they are fungible tokens,
+    /// and regenerate the icon once the symbols are known
+    pub(crate) fn fetch_component_metadata(&self) -> Promise {
+        let token_ids = self.set_info.token_ids();
+        let registry_queries = self.registry_queries(&token_ids);
+        utils::join_promises(
+            token_ids
+                .iter()
+                .map(|token_id| {
+                    ext_fungible_token::ft_metadata(token_id, NO_DEPOSIT, GAS_FOR_FT_METADATA)
+                })
+                .chain(registry_queries),
+        )
+        .then(ext_self::on_component_metadata(
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_COMPONENT_METADATA,
+        ))
+    }
+
+    /// Redraw the icon for the current composition if it is generated
+    pub(crate) fn refresh_icon(&mut self) {
+        if !self.icon.auto {
+            return;
+        }
+        let mut metadata = self.metadata.get().unwrap();
+        metadata.icon = Some(generate_icon(&self.set_info.get_ratios(), &self.icon.symbols));
+        self.internal_set_metadata(metadata);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Fetch the components' symbols again, e.g. after one of them changed its metadata
+    pub fn refresh_component_metadata(&self) -> Promise {
+        self.fetch_component_metadata()
+    }
+
+    #[private]
+    pub fn on_component_metadata(&mut self) {
+        let token_ids = self.set_info.token_ids();
+        let metadata: Vec<Option<FungibleTokenMetadata>> = (0..token_ids.len())
+            .map(|i| match env::promise_result(i as u64) {
+                PromiseResult::Successful(value) => serde_json::from_slice(&value).ok(),
+                _ => None,
+            })
+            .collect();
+        let is_token: Vec<bool> = metadata.iter().map(|metadata| metadata.is_some()).collect();
+        self.on_components_probed(&token_ids, &is_token);
+
+        self.icon.symbols = metadata
+            .into_iter()
+            .zip(token_ids.into_iter())
+            .map(|(metadata, token_id)| metadata.map(|m| m.symbol).unwrap_or(token_id))
+            .collect();
+        self.refresh_icon();
+    }
+
+    /// A human readable description of what backs the set token
+    pub fn get_set_description(&self) -> String {
+        let components: Vec<String> = self
+            .set_info
+            .get_ratios()
+            .iter()
+            .enumerate()
+            .map(|(i, token)| {
+                format!("{} {}", token.ratio, symbol_of(token, &self.icon.symbols, i))
+            })
+            .collect();
+        format!(
+            "Each unit of {} is backed by {} (in the tokens' smallest units)",
+            self.metadata.get().unwrap().symbol,
+            components.join(" + ")
+        )
+    }
+}
+
+fn symbol_of<'a>(token: &'a TokenWithRatio, symbols: &'a [String], i: usize) -> &'a str {
+    symbols.get(i).map(|symbol| symbol.as_str()).unwrap_or(&token.token_id)
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\'', "&apos;")
+}
+
+/// A deterministic SVG data URI for the given composition
+pub(crate) fn generate_icon(ratios: &[TokenWithRatio], symbols: &[String]) -> String {
+    let total: u128 = ratios.iter().map(|token| token.ratio as u128).sum();
+    let mut bands = String::new();
+    let mut cumulative: u128 = 0;
+    for token in ratios.iter() {
+        let x = cumulative * 100 / total;
+        cumulative += token.ratio as u128;
+        let width = cumulative * 100 / total - x;
+        let hash = env::sha256(token.token_id.as_bytes());
+        bands.push_str(&format!(
+            "<rect x='{}' y='0' width='{}' height='100' fill='#{:02x}{:02x}{:02x}'/>",
+            x, width, hash[0], hash[1], hash[2]
+        ));
+    }
+
+    let mut label: Vec<&str> = ratios
+        .iter()
+        .enumerate()
+        .take(MAX_ICON_SYMBOLS)
+        .map(|(i, token)| symbol_of(token, symbols, i))
+        .collect();
+    let more = format!("+{}", ratios.len().saturating_sub(MAX_ICON_SYMBOLS));
+    if ratios.len() > MAX_ICON_SYMBOLS {
+        label.push(&more);
+    }
+
+    let svg = format!(
+        "<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 100 100'>\
+         <clipPath id='c'><circle cx='50' cy='50' r='50'/></clipPath>\
+         <g clip-path='url(#c)'>{}</g>\
+         <text x='50' y='54' font-family='sans-serif' font-size='12' font-weight='bold' \
+         text-anchor='middle' fill='white' stroke='black' stroke-width='0.3'>{}</text></svg>",
+        bands,
+        escape_xml(&label.join("/"))
+    );
+    let encoded = svg
+        .replace('%', "%25")
+        .replace('<', "%3C")
+        .replace('>', "%3E")
+        .replace('#', "%23")
+        .replace('"', "%22");
+    format!("data:image/svg+xml,{}", encoded)
+}
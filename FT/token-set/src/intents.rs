@@ -0,0 +1,147 @@
+//! Wraps and unwraps signed off-chain and submitted by a relayer, for accounts holding no NEAR
+//! for gas.
+//!
+//! An account registers an ed25519 public key, then signs the Borsh encoding of an `Intent`. Any
+//! relayer can submit the intent with its signature, and it runs as if the account had called
+//! `wrap` or `unwrap` itself. The intent names the set contract, so that it cannot be replayed
+//! on another set, and carries a nonce which has to exceed the account's last one.
+use std::convert::TryFrom;
+
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{Base64VecU8, ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, PromiseOrValue, StorageUsage};
+
+use crate::utils;
+use crate::Contract;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum IntentAction {
+    Wrap,
+    Unwrap,
+}
+
+/// What an account signs to have a relayer wrap or unwrap for it
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Intent {
+    pub account_id: AccountId,
+    /// The set contract the intent is for
+    pub receiver_id: AccountId,
+    pub action: IntentAction,
+    /// The amount of set tokens, all that can be wrapped or unwrapped if `None`
+    pub amount: Option<U128>,
+    pub nonce: U64,
+    /// The block timestamp, in nanoseconds, after which the intent expires
+    pub deadline: U64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct IntentSigners {
+    keys: LookupMap<AccountId, Vec<u8>>,
+    /// The last nonce used by each account
+    nonces: LookupMap<AccountId, u64>,
+}
+
+impl IntentSigners {
+    pub(crate) fn new() -> Self {
+        Self { keys: LookupMap::new(b"ik".to_vec()), nonces: LookupMap::new(b"in".to_vec()) }
+    }
+}
+
+impl Contract {
+    fn assert_valid_signature(&self, intent: &Intent, public_key: &[u8], signature: &[u8]) {
+        if self.intent_signers.keys.get(&intent.account_id).as_deref() != Some(public_key) {
+            panic!("The public key is not registered by {}", intent.account_id);
+        }
+        let public_key = PublicKey::from_bytes(public_key).expect("Invalid public key");
+        let signature = Signature::try_from(signature).expect("Invalid signature");
+        let message = intent.try_to_vec().unwrap();
+        if public_key.verify(&message, &signature).is_err() {
+            panic!("The signature does not match the intent");
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Register the ed25519 public key the caller signs intents with, or remove it. The caller
+    /// pays for the storage.
+    #[payable]
+    pub fn set_intent_key(&mut self, public_key: Option<Base64VecU8>) {
+        utils::assert_at_least_1_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let account_id = env::predecessor_account_id();
+        match public_key {
+            Some(public_key) => {
+                PublicKey::from_bytes(&public_key.0).expect("Invalid public key");
+                self.intent_signers.keys.insert(&account_id, &public_key.0);
+                // Kept when the key is removed, so that old intents cannot be replayed
+                if self.intent_signers.nonces.get(&account_id).is_none() {
+                    self.intent_signers.nonces.insert(&account_id, &0);
+                }
+            }
+            None => {
+                self.intent_signers.keys.remove(&account_id);
+            }
+        }
+        if env::storage_usage() > initial_storage_usage {
+            utils::charge_storage(initial_storage_usage);
+        } else {
+            let released: StorageUsage = initial_storage_usage - env::storage_usage();
+            utils::refund_deposit(
+                env::attached_deposit() + released as u128 * env::storage_byte_cost(),
+            );
+        }
+    }
+
+    pub fn get_intent_key(&self, account_id: ValidAccountId) -> Option<Base64VecU8> {
+        self.intent_signers.keys.get(account_id.as_ref()).map(Base64VecU8)
+    }
+
+    /// The last nonce `account_id` used, the next intent needs a greater one
+    pub fn get_intent_nonce(&self, account_id: ValidAccountId) -> U64 {
+        self.intent_signers.nonces.get(account_id.as_ref()).unwrap_or(0).into()
+    }
+
+    /// Run an intent signed with the public key its account registered. Anyone can submit it.
+    ///
+    /// return the amount wrapped or unwrapped, or the transfers of the NFTs an unwrap of the
+    /// entire supply of an NFT-backed set returns to the account
+    pub fn execute_signed_intent(
+        &mut self,
+        intent: Intent,
+        public_key: Base64VecU8,
+        signature: Base64VecU8,
+    ) -> PromiseOrValue<U128> {
+        if intent.receiver_id != env::current_account_id() {
+            panic!("The intent is for {}", intent.receiver_id);
+        }
+        if env::block_timestamp() > intent.deadline.0 {
+            panic!("The intent expired");
+        }
+        self.assert_valid_signature(&intent, &public_key.0, &signature.0);
+        let account_id = intent.account_id.clone();
+        let last_nonce = self.intent_signers.nonces.get(&account_id).unwrap_or(0);
+        if intent.nonce.0 <= last_nonce {
+            panic!("The nonce has to be greater than {}", last_nonce);
+        }
+        self.intent_signers.nonces.insert(&account_id, &intent.nonce.0);
+
+        let amount = intent.amount.map(|amount| amount.0);
+        match intent.action {
+            IntentAction::Wrap => {
+                self.assert_can_wrap(&account_id);
+                self.assert_registered(&account_id);
+                PromiseOrValue::Value(self.internal_wrap(&account_id, amount, None).into())
+            }
+            IntentAction::Unwrap => match self.internal_unwrap(&account_id, amount) {
+                (_, Some(returned_nfts)) => returned_nfts.into(),
+                (amount, None) => PromiseOrValue::Value(amount.into()),
+            },
+        }
+    }
+}
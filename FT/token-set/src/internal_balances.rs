@@ -0,0 +1,63 @@
+//! The internal balances of deposited tokens, with the total of each token.
+//!
+//! Every deposit, withdrawal, wrap and burn goes through `InternalBalances`, which keeps the sum
+//! of the internal balances of a token next to them. The set holds these tokens on top of its
+//! reserves, so the totals are part of what `verify_backing` requires the set to hold and of what
+//! `skim` leaves untouched.
+//!
+//! Sets upgraded from `StateVersion::V1` did not keep the totals, so the internal balances
+//! credited before the upgrade are not part of them. The totals saturate at zero as these
+//! balances are spent.
+use near_internal_balance::ft::FungibleTokenBalances;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::{AccountId, Balance};
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct InternalBalances {
+    balances: FungibleTokenBalances,
+    /// The sum of the internal balances of each token
+    totals: LookupMap<AccountId, Balance>,
+}
+
+impl InternalBalances {
+    pub(crate) fn new() -> Self {
+        Self::with_balances(FungibleTokenBalances::new())
+    }
+
+    /// Track the totals from now on, starting from zero, for balances credited untracked
+    pub(crate) fn with_balances(balances: FungibleTokenBalances) -> Self {
+        Self { balances, totals: LookupMap::new(b"ibt".to_vec()) }
+    }
+
+    pub(crate) fn get_ft_balance(&self, account_id: &AccountId, token_id: &AccountId) -> Balance {
+        self.balances.get_ft_balance(account_id, token_id)
+    }
+
+    pub(crate) fn increase_balance(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        self.balances.increase_balance(account_id, token_id, amount);
+        let total = self.total_of(token_id);
+        self.totals.insert(token_id, &(total + amount));
+    }
+
+    pub(crate) fn subtract_balance(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        self.balances.subtract_balance(account_id, token_id, amount);
+        let total = self.total_of(token_id);
+        self.totals.insert(token_id, &total.saturating_sub(amount));
+    }
+
+    /// The sum of the internal balances of `token_id`
+    pub(crate) fn total_of(&self, token_id: &AccountId) -> Balance {
+        self.totals.get(token_id).unwrap_or(0)
+    }
+}
@@ -19,17 +19,84 @@ use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
 };
 use near_contract_standards::fungible_token::FungibleToken;
-use near_internal_balance::ft::{FungibleTokenBalances, FungibleTokenHandlers};
-use near_internal_balance::NearInternalBalance;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LazyOption, Vector};
+use near_sdk::collections::{LazyOption, LookupMap, LookupSet, Vector};
 use near_sdk::json_types::{ValidAccountId, U128};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, PanicOnDefault, Promise, PromiseOrValue};
 
+mod access;
+mod account_closed;
+mod allowance;
+mod allowlist;
+mod batch;
+mod checkpoints;
+mod deposits;
+mod events;
+mod external;
+mod fee_tiers;
+mod ft_core;
+mod governance;
+mod harvest;
+mod icon;
+mod intents;
+mod internal_balances;
+mod metadata;
+mod multi_token;
+mod multi_token_metadata;
+mod native;
+mod nested;
+mod nft;
+mod rebalance;
+mod rebalance_executor;
+mod referrals;
+mod reserves;
+mod roles;
+mod skim;
+mod storage;
+mod supply_caps;
+mod upgrade;
 mod token_set_info;
 mod utils;
 
+pub use access::AccessModes;
+pub use account_closed::AccountClosedPolicy;
+pub use allowlist::{ComponentAllowlist, ComponentsStatus};
+pub use batch::SetAction;
+pub use deposits::DepositMsg;
+pub use fee_tiers::{FeeTier, FeeTiersView, TierBasis};
+pub use governance::{GovernanceConfig, Proposal, ProposalKind, ProposalStatus, ProposalView};
+pub use harvest::{HarvestMode, YieldSource};
+pub use intents::{Intent, IntentAction};
+pub use metadata::FungibleTokenMetadataUpdate;
+pub use multi_token_metadata::{
+    MTBaseTokenMetadata, MTContractMetadata, MTTokenMetadata, MTTokenMetadataAll,
+};
+pub use nested::TokenAmount;
+pub use nft::{NftComponent, NftComponentView};
+pub use rebalance::{Price, PriceData, RebalanceConfig};
+pub use rebalance_executor::SwapLeg;
+pub use referrals::{ReferralConfig, ReferralEarnings};
+pub use reserves::{BackingReport, ComponentBacking};
+pub use roles::Role;
+pub use skim::SkimDestination;
+pub use supply_caps::SupplyCapsView;
+pub use upgrade::StateVersion;
+use access::AccessControl;
+use allowlist::ComponentVerification;
+use checkpoints::Checkpoints;
+use fee_tiers::FeeTiers;
+use governance::Governance;
+use harvest::Harvester;
+use icon::SetIcon;
+use intents::IntentSigners;
+use multi_token::MultiTokenHost;
+use nft::NftBacking;
+use rebalance::Rebalancer;
+use referrals::Referrals;
+use supply_caps::SupplyCaps;
+use token_set_info::FeeAdjustments;
+
 near_sdk::setup_alloc!();
 
 // TODO: do we bake in hardcoded fee to us????
@@ -50,29 +117,64 @@ pub struct FeeReceiver {
 pub struct TokenWithRatioValid {
     pub token_id: ValidAccountId,
     pub ratio: u32,
+    /// Whether the token is itself a token set
+    #[serde(default)]
+    pub is_set: bool,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct TokenWithRatio {
-    token_id: AccountId,
-    ratio: u32,
+    pub token_id: AccountId,
+    pub ratio: u32,
+    pub is_set: bool,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct SetInfo {
     ratios: Vector<TokenWithRatio>,
     fee: FeeReceiver,
+    caps: SupplyCaps,
+    /// The amount of each component backing the set tokens
+    reserves: LookupMap<AccountId, Balance>,
+    nfts: NftBacking,
+    /// Backing of each component donated to the holders on top of the ratios, per set token and
+    /// scaled by `DONATION_PRECISION`
+    donated: LookupMap<AccountId, Balance>,
+    /// The set tokens each account got by wrapping, which `max_account_mint` caps
+    minted: LookupMap<AccountId, Balance>,
 }
 
 #[near_bindgen]
-#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault, NearInternalBalance)]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
     owner_id: AccountId,
     token: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
-    balances: FungibleTokenBalances,
+    balances: InternalBalances,
     set_info: SetInfo,
+    rebalancer: Rebalancer,
+    account_closed_policy: AccountClosedPolicy,
+    /// Set tokens of force unregistered accounts, waiting to be claimed back
+    escrowed: LookupMap<AccountId, Balance>,
+    total_escrowed: Balance,
+    roles: LookupSet<(Role, AccountId)>,
+    icon: SetIcon,
+    verification: ComponentVerification,
+    access: AccessControl,
+    checkpoints: Checkpoints,
+    governance: Governance,
+    harvester: Harvester,
+    /// The wNEAR component native NEAR is wrapped into, if any
+    wnear_id: Option<AccountId>,
+    multi_token: MultiTokenHost,
+    /// The set tokens each spender can transfer for an owner, keyed by `(owner, spender)`
+    allowances: LookupMap<(AccountId, AccountId), Balance>,
+    intent_signers: IntentSigners,
+    referrals: Referrals,
+    fee_tiers: FeeTiers,
+    /// The storage deposit each account paid at registration, refunded when it unregisters
+    storage_paid: LookupMap<AccountId, Balance>,
 }
 
 #[near_bindgen]
@@ -90,6 +192,8 @@ impl Contract {
         platform_fee: U128,
         platform_id: ValidAccountId,
         owner_fee: U128,
+        account_closed_policy: Option<AccountClosedPolicy>,
+        component_allowlist: Option<ComponentAllowlist>,
     ) -> Self {
         Self::new(
             owner_id,
@@ -108,6 +212,8 @@ impl Contract {
                 owner_fee: owner_fee.0,
                 platform_id: platform_id.to_string(),
             },
+            account_closed_policy,
+            component_allowlist,
         )
     }
 
@@ -119,6 +225,8 @@ impl Contract {
         metadata: FungibleTokenMetadata,
         set_ratios: Vec<TokenWithRatioValid>,
         set_initial_fee: FeeReceiver,
+        account_closed_policy: Option<AccountClosedPolicy>,
+        component_allowlist: Option<ComponentAllowlist>,
     ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
 
@@ -128,19 +236,99 @@ impl Contract {
             owner_id: owner_id.to_string(),
             token: FungibleToken::new(b"a".to_vec()),
             metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
-            balances: FungibleTokenBalances::new(),
+            balances: InternalBalances::new(),
             set_info: SetInfo::new(set_ratios, set_initial_fee),
+            rebalancer: Rebalancer::new(),
+            account_closed_policy: account_closed_policy.unwrap_or_default(),
+            escrowed: LookupMap::new(b"esc".to_vec()),
+            total_escrowed: 0,
+            roles: LookupSet::new(b"roles".to_vec()),
+            icon: SetIcon { auto: metadata.icon.is_none(), symbols: vec![] },
+            verification: ComponentVerification::new(
+                component_allowlist,
+                ComponentsStatus::Pending,
+            ),
+            access: AccessControl::new(),
+            checkpoints: Checkpoints::new(),
+            governance: Governance::new(),
+            harvester: Harvester::new(),
+            wnear_id: None,
+            multi_token: MultiTokenHost::new(),
+            allowances: LookupMap::new(b"alw".to_vec()),
+            intent_signers: IntentSigners::new(),
+            referrals: Referrals::new(),
+            fee_tiers: FeeTiers::new(),
+            storage_paid: LookupMap::new(b"stp".to_vec()),
         };
-        
+        upgrade::write_state_version(upgrade::CURRENT_STATE_VERSION);
+        // Drawn from the token ids until the components' symbols come back
+        this.refresh_icon();
+        this.fetch_component_metadata();
+        // The fee receivers are paid in set tokens, so they have to be registered
+        for account_id in [this.owner_id.clone(), this.set_info.fee.platform_id.clone()].iter() {
+            if !this.is_registered(account_id) {
+                this.token.internal_register_account(account_id);
+                this.storage_paid.insert(account_id, &0);
+            }
+        }
         this
     }
 
+    /// Wrap the caller's internal balances into set tokens.
+    ///
+    /// An unregistered caller is registered first and pays the storage from the attached
+    /// deposit. Deposits through `ft_transfer_call` cannot register, see `deposits`. If the set
+    /// has a wNEAR component, as much of the rest of the deposit as the wrap needs is deposited
+    /// as wNEAR for the caller before wrapping, see `native`. What the wrap does not need is
+    /// refunded. A `referrer_id` earns part of the owner fee, see `referrals`.
+    #[payable]
+    pub fn wrap(
+        &mut self,
+        amount: Option<u128>,
+        referrer_id: Option<ValidAccountId>,
+    ) -> PromiseOrValue<()> {
+        utils::assert_at_least_1_yocto();
+        let caller = env::predecessor_account_id();
+        let referrer_id: Option<AccountId> = referrer_id.map(|id| id.into());
+        self.assert_can_wrap(&caller);
+        let storage_cost = self.register_with_deposit(&caller, env::attached_deposit());
+        let unused = env::attached_deposit() - storage_cost;
+        if unused > 1 && self.wnear_component().is_some() {
+            let deposit = std::cmp::min(unused - 1, self.near_to_wrap(&caller, amount));
+            if deposit > 0 {
+                utils::refund_deposit(unused - deposit);
+                return self.deposit_near_and_wrap(caller, deposit, amount, referrer_id).into();
+            }
+        }
+        self.internal_wrap(&caller, amount, referrer_id.as_ref());
+        utils::refund_deposit(unused);
+        PromiseOrValue::Value(())
+    }
+
+    /// Burn set tokens of the caller and credit them the underlying tokens. Unwraps the whole
+    /// balance if no amount is given. With `withdraw_near`, the wNEAR component is unwrapped and
+    /// sent as native NEAR instead of being credited. The NFTs of an NFT-backed set are sent to
+    /// the caller, who has to burn the entire supply.
     #[payable]
-    pub fn wrap(&mut self, amount: Option<u128>) {
+    pub fn unwrap(
+        &mut self,
+        amount: Option<u128>,
+        withdraw_near: Option<bool>,
+    ) -> PromiseOrValue<()> {
         utils::assert_1_yocto();
-        self.set_info.wrap(&self.owner_id, &mut self.token, &mut self.balances, amount);
+        let caller = env::predecessor_account_id();
+        let (amount, returned_nfts) = self.internal_unwrap(&caller, amount);
+        let mut promises: Vec<Promise> = returned_nfts.into_iter().collect();
+        if withdraw_near.unwrap_or(false) {
+            promises.push(self.withdraw_near(caller, amount));
+        }
+        if promises.is_empty() {
+            return PromiseOrValue::Value(());
+        }
+        utils::join_promises(promises.into_iter()).into()
     }
 
+    /// Lower the owner fee, the holders raise it through a `Fees` proposal
     #[payable]
     pub fn update_owner_fee(&mut self, new_fee: u128) {
         utils::assert_1_yocto();
@@ -153,13 +341,25 @@ impl Contract {
         self.set_info.change_owner_fee(new_fee);
     }
 
-    // TODO: let's think about,
-    // if there account was deleted that means we have to do something with the balance
-    // maybe we j transfer to platform?
+    /// The tokens making up the set and the amount of each backing one set token
+    pub fn get_set_ratios(&self) -> Vec<TokenWithRatio> {
+        self.set_info.get_ratios()
+    }
+
+    /// The amount of `token_id` credited to `account_id` and not yet wrapped or withdrawn
+    pub fn internal_balance_of(
+        &self,
+        account_id: ValidAccountId,
+        token_id: ValidAccountId,
+    ) -> U128 {
+        self.balances.get_ft_balance(account_id.as_ref(), token_id.as_ref()).into()
+    }
+
+    /// The balance of a force unregistered account is handled according to the
+    /// `AccountClosedPolicy` chosen at init
     fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
         log!("Closed @{} with {}", account_id, balance);
-        let platform_id = self.set_info.fee.platform_id.clone();
-        self.set_info.on_burn(&mut self.balances, platform_id, balance);
+        self.handle_account_closed(account_id, balance);
     }
 
     fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
@@ -168,8 +368,89 @@ impl Contract {
     }
 }
 
-near_contract_standards::impl_fungible_token_core!(Contract, token, on_tokens_burned);
-near_contract_standards::impl_fungible_token_storage!(Contract, token, on_account_closed);
+impl Contract {
+    pub(crate) fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can call this method"
+        );
+    }
+
+    pub(crate) fn assert_can_wrap(&self, caller: &AccountId) {
+        self.assert_components_verified();
+        self.assert_can_wrap_into(&self.set_info, caller);
+    }
+
+    /// The checks wrapping into the contract's own set and into hosted sets share
+    pub(crate) fn assert_can_wrap_into(&self, set_info: &SetInfo, caller: &AccountId) {
+        if set_info.nfts.is_fixed_supply() {
+            panic!("The supply of an NFT-backed set is issued when the NFTs are deposited");
+        }
+        self.rebalancer.assert_not_pending();
+        self.assert_can_mint(caller);
+    }
+
+    /// Wrap the internal balances of `caller`, who is registered already
+    ///
+    /// return the amount minted, fees included
+    pub(crate) fn internal_wrap(
+        &mut self,
+        caller: &AccountId,
+        amount: Option<u128>,
+        referrer_id: Option<&AccountId>,
+    ) -> Balance {
+        let adjustments = FeeAdjustments {
+            referral_bps: self.referral_rate(caller, referrer_id),
+            discount_bps: self.fee_discount(caller),
+        };
+        let account_ids =
+            [caller.clone(), self.owner_id.clone(), self.set_info.fee.platform_id.clone()];
+        let (amount, referral_fee) = self.checkpointed(&account_ids, |this| {
+            let owner_id = &this.owner_id;
+            this.set_info.wrap(
+                owner_id,
+                caller,
+                &mut this.token,
+                &mut this.balances,
+                amount,
+                adjustments,
+            )
+        });
+        if let Some(referrer_id) = referrer_id {
+            self.credit_referral(referrer_id, caller, referral_fee);
+        }
+        amount
+    }
+
+    /// Set tokens which are backed but not minted yet: the escrowed ones and the referral
+    /// rewards
+    pub(crate) fn unclaimed_supply(&self) -> Balance {
+        self.total_escrowed + self.referrals.total_claimable
+    }
+
+    /// Burn set tokens of `account_id` and credit it the underlying tokens. Burning the supply of
+    /// an NFT-backed set also sends it the NFTs.
+    ///
+    /// return the amount burned and the transfers of the NFTs
+    pub(crate) fn internal_unwrap(
+        &mut self,
+        account_id: &AccountId,
+        amount: Option<u128>,
+    ) -> (Balance, Option<Promise>) {
+        self.rebalancer.assert_not_pending();
+        let fixed_supply = self.set_info.nfts.is_fixed_supply();
+        if fixed_supply {
+            self.assert_full_redemption(account_id, amount);
+        }
+        let amount = self.checkpointed(&[account_id.clone()], |this| {
+            this.set_info.burn(&mut this.token, account_id, amount)
+        });
+        self.set_info.on_burn(&mut self.balances, account_id.clone(), amount);
+        let returned_nfts = if fixed_supply { Some(self.return_nfts(account_id)) } else { None };
+        (amount, returned_nfts)
+    }
+}
 
 #[near_bindgen]
 impl FungibleTokenMetadataProvider for Contract {
@@ -180,6 +461,9 @@ impl FungibleTokenMetadataProvider for Contract {
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::json_types::Base64VecU8;
     use near_sdk::test_utils::{accounts, VMContextBuilder};
     use near_sdk::MockedBlockchain;
     use near_sdk::{testing_env, Balance};
@@ -195,29 +479,82 @@ mod tests {
         builder
     }
 
-    #[test]
-    fn test_new() {
-        let mut context = get_context(accounts(1));
-        testing_env!(context.build());
+    /// What a test set differs in from the default one of `setup_contract`
+    struct SetupOptions {
+        owner_fee: Balance,
+        account_closed_policy: Option<AccountClosedPolicy>,
+        allowlist: Option<ComponentAllowlist>,
+        /// Whether the components answered their `ft_metadata` probe
+        verified: bool,
+    }
 
-        let platform_id = accounts(4);
-        let token_id = accounts(5);
+    impl Default for SetupOptions {
+        fn default() -> Self {
+            Self { owner_fee: 0, account_closed_policy: None, allowlist: None, verified: true }
+        }
+    }
+
+    /// A set owned by `accounts(2)` with `accounts(4)` as platform, no fees and components which
+    /// answered their `ft_metadata` probe
+    fn setup_contract(set_ratios: Vec<TokenWithRatioValid>) -> Contract {
+        setup_contract_with(set_ratios, SetupOptions::default())
+    }
 
-        let contract = Contract::new_default_meta(
+    fn setup_contract_with(
+        set_ratios: Vec<TokenWithRatioValid>,
+        options: SetupOptions,
+    ) -> Contract {
+        let mut contract = Contract::new_default_meta(
             accounts(2).into(),
             "YOUR MOM".to_string(),
             "YOUR MOM".to_string(),
             None,
-            vec![TokenWithRatioValid { token_id, ratio: 1 }],
-            0.into(),
-            platform_id,
+            set_ratios,
             0.into(),
+            accounts(4),
+            options.owner_fee.into(),
+            options.account_closed_policy,
+            options.allowlist,
         );
+        if options.verified {
+            contract.verification.status = ComponentsStatus::Verified;
+        }
+        contract
+    }
+
+    #[test]
+    fn test_new() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+
+        let token_id = accounts(5);
+
+        let contract =
+            setup_contract(vec![TokenWithRatioValid { token_id, ratio: 1, is_set: false }]);
         testing_env!(context.is_view(true).build());
         assert_eq!(contract.ft_total_supply().0, 0);
         assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
     }
 
+    #[test]
+    fn test_storage_bounds_count_components() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let new_set = |token_ids: Vec<ValidAccountId>| {
+            setup_contract(
+                token_ids
+                    .into_iter()
+                    .map(|token_id| TokenWithRatioValid { token_id, ratio: 1, is_set: false })
+                    .collect(),
+            )
+        };
+        let min_one = new_set(vec![accounts(5)]).storage_balance_bounds().min.0;
+        let min_three =
+            new_set(vec![accounts(1), accounts(3), accounts(5)]).storage_balance_bounds().min.0;
+        assert!(min_three > min_one);
+        assert_eq!((min_three - min_one) % env::storage_byte_cost(), 0);
+    }
+
     #[test]
     #[should_panic(expected = "The contract is not initialized")]
     fn test_default() {
@@ -230,18 +567,12 @@ mod tests {
     fn test_wrap_transfer() {
         let mut context = get_context(accounts(2));
         testing_env!(context.build());
-        let platform_id = accounts(4);
         let token_id = accounts(5);
-        let mut contract = Contract::new_default_meta(
-            accounts(2).into(),
-            "YOUR MOM".to_string(),
-            "YOUR MOM".to_string(),
-            None,
-            vec![TokenWithRatioValid { token_id: token_id.clone(), ratio: 1 }],
-            0.into(),
-            platform_id,
-            0.into(),
-        );
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
 
         let amount_transfer = 100;
         contract.balances.increase_balance(
@@ -250,82 +581,799 @@ mod tests {
             amount_transfer,
         );
 
+        // The owner and the platform are registered at init and wrapping registers the caller,
+        // paying from the attached deposit
         testing_env!(context
             .storage_usage(env::storage_usage())
-            .attached_deposit(contract.storage_balance_bounds().min.into())
-            .predecessor_account_id(accounts(2))
+            .attached_deposit(contract.storage_balance_bounds().min.0 + 1_000)
+            .predecessor_account_id(accounts(1))
             .build());
-        // Paying for account registration, aka storage deposit
-        contract.storage_deposit(None, None);
+        contract.wrap(None, None);
+        assert!(contract.storage_balance_of(accounts(1)).is_some());
+        assert_eq!(
+            contract
+                .balances
+                .get_ft_balance(&accounts(1).to_string(), &token_id.clone().to_string()),
+            0
+        );
+
+        // TODO: with wrap
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, amount_transfer);
+        contract.ft_transfer(accounts(2), amount_transfer.into(), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .account_balance(env::account_balance())
+            .is_view(true)
+            .attached_deposit(0)
+            .build());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, (0));
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, amount_transfer);
+    }
+
+    #[test]
+    fn test_update_metadata() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: accounts(5),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.update_metadata(FungibleTokenMetadataUpdate {
+            name: Some("Index".to_string()),
+            reference: Some("https://example.com/methodology.json".to_string()),
+            reference_hash: Some(vec![0; 32].into()),
+            ..Default::default()
+        });
+        contract.grant_role(Role::Metadata, accounts(3));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.update_metadata(FungibleTokenMetadataUpdate {
+            symbol: Some("IDX".to_string()),
+            ..Default::default()
+        });
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.name, "Index");
+        assert_eq!(metadata.symbol, "IDX");
+        assert_eq!(metadata.reference, Some("https://example.com/methodology.json".to_string()));
+
+        let metadata = contract.update_metadata(FungibleTokenMetadataUpdate {
+            clear_icon: Some(true),
+            clear_reference: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(metadata.icon, None);
+        assert_eq!(metadata.reference, None);
+        assert_eq!(metadata.reference_hash, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "attached to register")]
+    fn test_wrap_without_storage_deposit() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.wrap(None, None);
+    }
+
+    #[test]
+    fn test_generated_icon() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = setup_contract(vec![
+            TokenWithRatioValid { token_id: accounts(1), ratio: 1, is_set: false },
+            TokenWithRatioValid { token_id: accounts(5), ratio: 3, is_set: false },
+        ]);
+        let icon = contract.ft_metadata().icon.unwrap();
+        assert!(icon.starts_with("data:image/svg+xml,%3Csvg"));
+        assert!(icon.contains("width='25'") && icon.contains("width='75'"));
+        assert!(icon.contains("bob/fargo"));
+        assert_eq!(
+            contract.get_set_description(),
+            "Each unit of YOUR MOM is backed by 1 bob + 3 fargo (in the tokens' smallest units)"
+        );
+
+        contract.update_metadata(FungibleTokenMetadataUpdate {
+            icon: Some("https://example.com/icon.svg".to_string()),
+            ..Default::default()
+        });
+        contract.refresh_icon();
+        assert_eq!(contract.ft_metadata().icon.unwrap(), "https://example.com/icon.svg");
+
+        contract.update_metadata(FungibleTokenMetadataUpdate {
+            auto_icon: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(contract.ft_metadata().icon.unwrap(), icon);
+    }
+
+    #[test]
+    fn test_local_component_allowlist() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract_with(
+            vec![TokenWithRatioValid { token_id: token_id.clone(), ratio: 1, is_set: false }],
+            SetupOptions {
+                allowlist: Some(ComponentAllowlist::Local),
+                verified: false,
+                ..Default::default()
+            },
+        );
+        let token_ids = vec![token_id.to_string()];
+        assert_eq!(contract.get_components_status(), ComponentsStatus::Pending);
+        contract.on_components_probed(&token_ids, &[true]);
+        assert_eq!(contract.get_components_status(), ComponentsStatus::Rejected);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.add_allowed_tokens(vec![token_id.clone()]);
+        contract.on_components_probed(&token_ids, &[false]);
+        assert_eq!(contract.get_components_status(), ComponentsStatus::Rejected);
+        contract.on_components_probed(&token_ids, &[true]);
+        assert_eq!(contract.get_components_status(), ComponentsStatus::Verified);
+    }
+
+    #[test]
+    #[should_panic(expected = "components are not verified")]
+    fn test_wrap_unverified_components() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = setup_contract_with(
+            vec![TokenWithRatioValid { token_id: accounts(5), ratio: 1, is_set: false }],
+            SetupOptions { verified: false, ..Default::default() },
+        );
+        testing_env!(context.attached_deposit(1).build());
+        contract.wrap(None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "would exceed the maximum of 100 per account")]
+    fn test_supply_caps() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.set_supply_caps(Some(150.into()), Some(100.into()));
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 300);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.wrap(Some(100), None);
+        let caps = contract.get_supply_caps();
+        assert_eq!(caps.total_supply_headroom, Some(50.into()));
+        assert_eq!(contract.get_mint_headroom(accounts(1)), Some(0.into()));
+        assert_eq!(contract.get_mint_headroom(accounts(3)), Some(50.into()));
+
+        // Unwrapping does not give back what was minted
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.unwrap(Some(50), None);
+        assert_eq!(contract.get_mint_headroom(accounts(1)), Some(0.into()));
+        contract.wrap(Some(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The owner can only add or lower caps")]
+    fn test_owner_cannot_raise_supply_caps() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: accounts(5),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.set_supply_caps(Some(150.into()), None);
+        contract.set_supply_caps(Some(100.into()), Some(50.into()));
+        contract.set_supply_caps(None, Some(50.into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "The owner can only lower the owner fee")]
+    fn test_owner_cannot_raise_fee() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: accounts(5),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.update_owner_fee(0);
+        contract.update_owner_fee(1);
+    }
+
+    #[test]
+    fn test_access_modes() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.grant_role(Role::Allowlist, accounts(3));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let modes = AccessModes { mint_restricted: true, transfer_restricted: false };
+        contract.set_access_modes(modes);
+        contract.add_to_allowlist(vec![accounts(1), accounts(4)]);
+        contract.remove_from_allowlist(vec![accounts(4)]);
+        assert!(contract.is_allowlisted(accounts(1)));
+        assert!(!contract.is_allowlisted(accounts(4)));
+
+        // Allowlisted accounts mint and can transfer to anyone
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.wrap(None, None);
+        testing_env!(context.attached_deposit(1).build());
+        contract.ft_transfer(accounts(2), 40.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 40);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not allowed to hold the set token")]
+    fn test_transfer_restricted() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: accounts(5),
+            ratio: 1,
+            is_set: false,
+        }]);
+        let modes = AccessModes { mint_restricted: false, transfer_restricted: true };
+        contract.set_access_modes(modes);
+        contract.add_to_allowlist(vec![accounts(2)]);
+        contract.ft_transfer(accounts(4), 0.into(), None);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_index(10).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
+        testing_env!(context
+            .block_index(12)
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.wrap(None, None);
+        testing_env!(context.block_index(15).attached_deposit(1).build());
+        contract.ft_transfer(accounts(2), 40.into(), None);
+
+        let balance_at = |account_id: ValidAccountId, block_height: u64| {
+            contract.balance_of_at(account_id, block_height.into()).0
+        };
+        assert_eq!(balance_at(accounts(1), 11), 0);
+        assert_eq!(balance_at(accounts(1), 12), 100);
+        assert_eq!(balance_at(accounts(1), 14), 100);
+        assert_eq!(balance_at(accounts(1), 15), 60);
+        assert_eq!(balance_at(accounts(2), 14), 0);
+        assert_eq!(balance_at(accounts(2), 20), 40);
+        assert_eq!(contract.total_supply_at(11.into()).0, 0);
+        assert_eq!(contract.total_supply_at(15.into()).0, 100);
+    }
+
+    #[test]
+    fn test_distributed_rewards() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let reward_token_id = accounts(3).to_string();
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.set_yield_source(YieldSource {
+            component_id: token_id.to_string(),
+            claim_method: "claim".to_string(),
+            claim_args: "{}".to_string(),
+            claim_deposit: 1.into(),
+            reward_token_id: reward_token_id.clone(),
+            mode: HarvestMode::Distribute,
+        });
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
 
         testing_env!(context
             .storage_usage(env::storage_usage())
-            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.wrap(None, None);
+        contract.distribute(&reward_token_id, 50);
+        testing_env!(context.attached_deposit(1).build());
+        contract.ft_transfer(accounts(2), 50.into(), None);
+        contract.distribute(&reward_token_id, 30);
+
+        let claimable = |contract: &Contract, account_id: ValidAccountId| {
+            contract.get_claimable_rewards(account_id, accounts(3)).0
+        };
+        assert_eq!(claimable(&contract, accounts(1)), 65);
+        assert_eq!(claimable(&contract, accounts(2)), 15);
+        assert_eq!(contract.claim_rewards(accounts(3)).0, 65);
+        assert_eq!(claimable(&contract, accounts(1)), 0);
+        assert_eq!(contract.internal_balance_of(accounts(1), accounts(3)).0, 65);
+    }
+
+    #[test]
+    fn test_multi_token_host() {
+        let mut context = get_context(accounts(4));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10u128.pow(24))
+            .build());
+        let metadata = contract.ft_metadata();
+        let ratios =
+            vec![TokenWithRatioValid { token_id: token_id.clone(), ratio: 2, is_set: false }];
+        contract.mt_create_set("duo".to_string(), accounts(3), metadata, ratios, 0.into());
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .predecessor_account_id(accounts(1))
+            .build());
+        assert_eq!(contract.mt_wrap("duo".to_string(), None).0, 50);
+        assert_eq!(contract.internal_balance_of(accounts(1), token_id.clone()).0, 0);
+        // The hosted set's components are accounted for apart from the host's own
+        assert_eq!(contract.get_accounted_balance(token_id.clone()).0, 100);
+
+        testing_env!(context.attached_deposit(1).build());
+        contract.mt_transfer(accounts(3), "duo".to_string(), 20.into(), None, None);
+        let balances = contract.mt_batch_balance_of(accounts(1), vec!["duo".to_string()]);
+        assert_eq!(balances, vec![U128(30)]);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        assert_eq!(contract.mt_unwrap("duo".to_string(), None).0, 20);
+        assert_eq!(contract.internal_balance_of(accounts(3), token_id).0, 40);
+        assert_eq!(contract.mt_supply("duo".to_string()), Some(U128(30)));
+        assert_eq!(contract.mt_supply("trio".to_string()), None);
+        let metadata =
+            contract.mt_metadata_base_by_token_id(vec!["duo".to_string(), "trio".to_string()]);
+        assert_eq!(metadata[0].as_ref().map(|base| base.id.as_str()), Some("duo"));
+        assert!(metadata[1].is_none());
+    }
+
+    /// Host the set "duo" of accounts(5), owned by accounts(3) and with a 10% owner fee
+    fn setup_hosted_set(context: &mut VMContextBuilder) -> Contract {
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: accounts(5),
+            ratio: 1,
+            is_set: false,
+        }]);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
             .predecessor_account_id(accounts(4))
+            .attached_deposit(10u128.pow(24))
+            .build());
+        let metadata = contract.ft_metadata();
+        let ratios = vec![TokenWithRatioValid { token_id: accounts(5), ratio: 1, is_set: false }];
+        let owner_fee = 100_000_000_000_000;
+        contract.mt_create_set("duo".to_string(), accounts(3), metadata, ratios, owner_fee.into());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "The owner can only lower the owner fee")]
+    fn test_hosted_owner_cannot_raise_fee() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = setup_hosted_set(&mut context);
+        contract.mt_set_owner_fee("duo".to_string(), 50_000_000_000_000.into());
+        contract.mt_set_owner_fee("duo".to_string(), 200_000_000_000_000.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "The owner can only add or lower caps")]
+    fn test_hosted_owner_cannot_raise_supply_caps() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = setup_hosted_set(&mut context);
+        contract.mt_set_supply_caps("duo".to_string(), Some(100.into()), None);
+        contract.mt_set_supply_caps("duo".to_string(), Some(200.into()), None);
+    }
+
+    #[test]
+    fn test_nft_backed_set() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        let nft = |token_id: &str| NftComponent {
+            contract_id: accounts(3).to_string(),
+            token_id: token_id.to_string(),
+        };
+        contract.set_nft_components(vec![nft("1"), nft("2")], 10.into());
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 10);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .predecessor_account_id(accounts(1))
             .build());
-        // Paying for account registration, aka storage deposit
         contract.storage_deposit(None, None);
 
+        testing_env!(context.attached_deposit(0).predecessor_account_id(accounts(3)).build());
+        let deposit = |contract: &mut Contract, token_id: &str| {
+            let from = accounts(1).to_string();
+            contract.nft_on_transfer(from.clone(), from, token_id.to_string(), "".to_string())
+        };
+        assert!(matches!(deposit(&mut contract, "3"), PromiseOrValue::Value(true)));
+        assert!(matches!(deposit(&mut contract, "1"), PromiseOrValue::Value(false)));
+        assert_eq!(contract.ft_total_supply().0, 0);
+        // The last NFT issues the whole supply
+        assert!(matches!(deposit(&mut contract, "2"), PromiseOrValue::Value(false)));
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10);
+        assert_eq!(contract.internal_balance_of(accounts(1), token_id.clone()).0, 0);
+
+        testing_env!(context.attached_deposit(1).predecessor_account_id(accounts(1)).build());
+        contract.unwrap(None, None);
+        assert_eq!(contract.ft_total_supply().0, 0);
+        assert_eq!(contract.internal_balance_of(accounts(1), token_id).0, 10);
+        assert!(contract.get_nft_components().iter().all(|nft| !nft.held));
+    }
+
+    #[test]
+    fn test_allowance() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
         testing_env!(context
             .storage_usage(env::storage_usage())
-            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
             .predecessor_account_id(accounts(1))
             .build());
-        // Paying for account registration, aka storage deposit
+        contract.wrap(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10u128.pow(22))
+            .build());
+        contract.ft_approve(accounts(3), 60.into());
+        assert_eq!(contract.ft_allowance(accounts(1), accounts(3)).0, 60);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.ft_transfer_from(accounts(1), accounts(2), 50.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 50);
+        assert_eq!(contract.ft_allowance(accounts(1), accounts(3)).0, 10);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_approve(accounts(3), 0.into());
+        assert_eq!(contract.ft_allowance(accounts(1), accounts(3)).0, 0);
+    }
+
+    #[test]
+    fn test_execute_batch() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
+        let min_balance = contract.storage_balance_bounds().min.0;
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(min_balance)
+            .predecessor_account_id(accounts(3))
+            .build());
         contract.storage_deposit(None, None);
-        assert_eq!(
-            contract
-                .balances
-                .get_ft_balance(&accounts(1).to_string(), &token_id.clone().to_string()),
-            amount_transfer
+
+        // Registers the caller, wraps, sends on part of the set tokens and withdraws the dust
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(min_balance + 2)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.execute_batch(vec![
+            SetAction::Wrap { amount: Some(60.into()), referrer_id: None },
+            SetAction::FtTransfer { receiver_id: accounts(3), amount: 50.into(), memo: None },
+            SetAction::Withdraw { token_id: token_id.clone(), amount: None },
+        ]);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 50);
+        assert_eq!(contract.internal_balance_of(accounts(1), token_id).0, 0);
+    }
+
+    #[test]
+    fn test_referral_rewards() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        // A 10% owner fee
+        let mut contract = setup_contract_with(
+            vec![TokenWithRatioValid { token_id: token_id.clone(), ratio: 1, is_set: false }],
+            SetupOptions { owner_fee: 100_000_000_000_000, ..Default::default() },
         );
+        contract.set_referral_rate(5_000);
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
 
         testing_env!(context
             .storage_usage(env::storage_usage())
-            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
             .predecessor_account_id(accounts(1))
             .build());
-        // Paying for account registration, aka storage deposit
-        contract.storage_deposit(None, None);
-        assert_eq!(
-            contract
-                .balances
-                .get_ft_balance(&accounts(1).to_string(), &token_id.clone().to_string()),
-            amount_transfer
+        contract.wrap(None, Some(accounts(3)));
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 90);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 5);
+        let earnings = contract.get_referral_earnings(accounts(3));
+        assert_eq!((earnings.claimable.0, earnings.total_earned.0), (5, 5));
+        assert_eq!(contract.unclaimed_supply(), 5);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .predecessor_account_id(accounts(3))
+            .build());
+        assert_eq!(contract.claim_referral_rewards().0, 5);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 5);
+        let earnings = contract.get_referral_earnings(accounts(3));
+        assert_eq!((earnings.claimable.0, earnings.total_earned.0), (0, 5));
+        assert_eq!(contract.unclaimed_supply(), 0);
+    }
+
+    #[test]
+    fn test_deposit_wraps_with_referrer() {
+        use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        // A 10% owner fee
+        let mut contract = setup_contract_with(
+            vec![TokenWithRatioValid { token_id: token_id.clone(), ratio: 1, is_set: false }],
+            SetupOptions { owner_fee: 100_000_000_000_000, ..Default::default() },
         );
+        contract.set_referral_rate(5_000);
 
         testing_env!(context
             .storage_usage(env::storage_usage())
-            .attached_deposit(1)
+            .attached_deposit(contract.storage_balance_bounds().min.0)
             .predecessor_account_id(accounts(1))
             .build());
-        // Paying for account registration, aka storage deposit
+        contract.storage_deposit(None, None);
 
-        contract.wrap(None);
-        assert_eq!(
-            contract
-                .balances
-                .get_ft_balance(&accounts(1).to_string(), &token_id.clone().to_string()),
-            0
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(0)
+            .predecessor_account_id(token_id.clone())
+            .build());
+        let msg = format!(r#"{{"wrap": true, "referrer_id": "{}"}}"#, accounts(3));
+        contract.ft_on_transfer(accounts(1), 100.into(), msg);
+        assert_eq!(contract.internal_balance_of(accounts(1), token_id).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 90);
+        assert_eq!(contract.get_referral_earnings(accounts(3)).claimable.0, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only deposits for the sender can be wrapped")]
+    fn test_deposit_cannot_wrap_for_another_account() {
+        use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+
+        // Danny deposits for Bob and tries to wrap it, minting for Bob and earning the referral
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(0)
+            .predecessor_account_id(token_id)
+            .build());
+        let msg = format!(
+            r#"{{"sender_id": "{}", "wrap": true, "referrer_id": "{}"}}"#,
+            accounts(1),
+            accounts(3)
         );
+        contract.ft_on_transfer(accounts(3), 100.into(), msg);
+    }
 
-        // TODO: with wrap
+    #[test]
+    fn test_fee_tiers() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let token_id = accounts(5);
+        // A 10% owner fee
+        let mut contract = setup_contract_with(
+            vec![TokenWithRatioValid { token_id: token_id.clone(), ratio: 1, is_set: false }],
+            SetupOptions { owner_fee: 100_000_000_000_000, ..Default::default() },
+        );
+        let tiers = vec![FeeTier { min_balance: 50.into(), discount_bps: 5_000 }];
+        contract.set_fee_tiers(TierBasis::SetBalance { holding_blocks: 0.into() }, tiers);
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 200);
 
         testing_env!(context
             .storage_usage(env::storage_usage())
-            .attached_deposit(1)
+            .attached_deposit(contract.storage_balance_bounds().min.0)
             .predecessor_account_id(accounts(1))
             .build());
-        assert_eq!(contract.ft_balance_of(accounts(1)).0, amount_transfer);
-        contract.ft_transfer(accounts(2), amount_transfer.into(), None);
+        contract.wrap(Some(100), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 90);
+        assert_eq!(contract.get_fee_discount(accounts(1)), 5_000);
+
+        // Holding 90 set tokens halves the fee
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.wrap(Some(100), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 185);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 15);
+    }
 
+    /// A contract on which accounts(1) signed an intent to wrap 60 of its 100 deposited tokens,
+    /// along with the intent, its public key and its signature
+    fn setup_signed_intent(
+        context: &mut VMContextBuilder,
+    ) -> (Contract, Intent, Base64VecU8, Base64VecU8) {
+        use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+
+        let token_id = accounts(5);
+        let mut contract = setup_contract(vec![TokenWithRatioValid {
+            token_id: token_id.clone(),
+            ratio: 1,
+            is_set: false,
+        }]);
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 100);
+
+        let secret = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let keypair = Keypair { secret, public };
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.0)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10u128.pow(22))
+            .build());
+        contract.set_intent_key(Some(Base64VecU8(public.to_bytes().to_vec())));
+
+        let intent = Intent {
+            account_id: accounts(1).into(),
+            receiver_id: accounts(0).into(),
+            action: IntentAction::Wrap,
+            amount: Some(60.into()),
+            nonce: 1.into(),
+            deadline: 100.into(),
+        };
+        let signature = keypair.sign(&intent.try_to_vec().unwrap());
+        let public_key = Base64VecU8(public.to_bytes().to_vec());
+        let signature = Base64VecU8(signature.to_bytes().to_vec());
+
+        // Relayed by an account with no stake in the set
         testing_env!(context
             .storage_usage(env::storage_usage())
-            .account_balance(env::account_balance())
-            .is_view(true)
             .attached_deposit(0)
+            .predecessor_account_id(accounts(3))
             .build());
-        assert_eq!(contract.ft_balance_of(accounts(1)).0, (0));
-        assert_eq!(contract.ft_balance_of(accounts(2)).0, amount_transfer);
+        (contract, intent, public_key, signature)
+    }
+
+    #[test]
+    fn test_signed_intent() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let (mut contract, intent, public_key, signature) = setup_signed_intent(&mut context);
+        contract.execute_signed_intent(intent, public_key, signature);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 60);
+        assert_eq!(contract.internal_balance_of(accounts(1), accounts(5)).0, 40);
+        assert_eq!(contract.get_intent_nonce(accounts(1)).0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "The nonce has to be greater than 1")]
+    fn test_signed_intent_replay() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.attached_deposit(1).build());
+        let (mut contract, intent, public_key, signature) = setup_signed_intent(&mut context);
+        contract.execute_signed_intent(intent.clone(), public_key.clone(), signature.clone());
+        contract.execute_signed_intent(intent, public_key, signature);
+    }
+
+    #[test]
+    fn test_donation_keeps_fractional_backing() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let token_id = accounts(5);
+        let mut contract = setup_contract_with(
+            vec![TokenWithRatioValid { token_id: token_id.clone(), ratio: 1, is_set: false }],
+            SetupOptions {
+                account_closed_policy: Some(AccountClosedPolicy::DonateToHolders),
+                ..Default::default()
+            },
+        );
+        contract.balances.increase_balance(&accounts(1).to_string(), &token_id.to_string(), 3);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.0 + 1)
+            .build());
+        contract.wrap(None, None);
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.ft_transfer(accounts(2), 2.into(), None);
+        contract.storage_unregister(Some(true));
+
+        // The remaining 2 set tokens share the 3 underlying tokens, a ratio of 1.5
+        assert_eq!(contract.get_set_ratios()[0].ratio, 1);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.unwrap(None, None);
+        assert_eq!(contract.internal_balance_of(accounts(2), token_id).0, 3);
     }
 }
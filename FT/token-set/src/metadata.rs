@@ -0,0 +1,87 @@
+//! Updates of the set token's metadata after init.
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::roles::Role;
+use crate::utils;
+use crate::{events, Contract};
+
+/// The metadata fields to change, the ones left out are kept. `reference` points to an off-chain
+/// JSON describing the set's methodology and has to come with its `reference_hash`.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FungibleTokenMetadataUpdate {
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+    /// Go back to an icon generated from the components
+    pub auto_icon: Option<bool>,
+    /// Remove the icon, without generating one
+    pub clear_icon: Option<bool>,
+    /// Remove the reference and its hash
+    pub clear_reference: Option<bool>,
+}
+
+impl Contract {
+    /// Validate and store new metadata, logging the change
+    pub(crate) fn internal_set_metadata(&mut self, metadata: FungibleTokenMetadata) {
+        metadata.assert_valid();
+        self.metadata.set(&metadata);
+        events::emit("ft_metadata_update", &metadata);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn update_metadata(
+        &mut self,
+        update: FungibleTokenMetadataUpdate,
+    ) -> FungibleTokenMetadata {
+        utils::assert_1_yocto();
+        self.assert_owner_or_role(Role::Metadata);
+        let clear_icon = update.clear_icon.unwrap_or(false);
+        let clear_reference = update.clear_reference.unwrap_or(false);
+        if clear_icon && (update.icon.is_some() || update.auto_icon == Some(true)) {
+            panic!("Cannot set and clear the icon at once");
+        }
+        if clear_reference && (update.reference.is_some() || update.reference_hash.is_some()) {
+            panic!("Cannot set and clear the reference at once");
+        }
+        let mut metadata = self.metadata.get().unwrap();
+        if let Some(name) = update.name {
+            metadata.name = name;
+        }
+        if let Some(symbol) = update.symbol {
+            metadata.symbol = symbol;
+        }
+        if let Some(icon) = update.icon {
+            metadata.icon = Some(icon);
+            self.icon.auto = false;
+        }
+        if let Some(reference) = update.reference {
+            metadata.reference = Some(reference);
+        }
+        if let Some(reference_hash) = update.reference_hash {
+            metadata.reference_hash = Some(reference_hash);
+        }
+        if clear_icon {
+            metadata.icon = None;
+            self.icon.auto = false;
+        }
+        if clear_reference {
+            metadata.reference = None;
+            metadata.reference_hash = None;
+        }
+        self.internal_set_metadata(metadata);
+        if update.auto_icon == Some(true) {
+            self.icon.auto = true;
+            self.refresh_icon();
+        }
+        self.metadata.get().unwrap()
+    }
+}
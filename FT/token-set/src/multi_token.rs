@@ -0,0 +1,423 @@
+//! Hosting many sets in one contract, exposed through the NEP-245 multi token interface.
+//!
+//! Besides its own set token, the contract can host further sets keyed by a `token_id`, each with
+//! its own components, owner fee, supply caps, owner and metadata, so that a platform does not
+//! have to deploy and stake storage for one contract per set. Hosted sets are created by the
+//! platform, which vets their components, and pay it the host's platform fee. The owner of a
+//! hosted set can lower its owner fee and tighten its supply caps, but as hosted sets have no
+//! governance, never raise them.
+//!
+//! Components are deposited through `ft_transfer_call` into the same internal balances as for the
+//! contract's own set, naming the hosted set in the message's `mt_token_id`. Registering with a
+//! hosted set pays for an internal balance of each of its components and a mint record, and only
+//! registered accounts are credited, by deposits as well as by `mt_unwrap`. `mt_wrap` and
+//! `mt_unwrap` reuse the wrap and burn logic, and wrapping goes through the checks of the
+//! contract's own set: the components have to be on the local allowlist if there is one, the
+//! access modes apply and nothing is wrapped while the contract's set is being rebalanced.
+//! Rebalancing, harvesting, governance and checkpoints only apply to the contract's own set. The
+//! NEP-245 metadata of the hosted sets is served by `multi_token_metadata`.
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::Serialize;
+use near_sdk::{env, near_bindgen, AccountId, Balance, StorageUsage};
+
+use crate::storage::{INTERNAL_BALANCE_STORAGE_USAGE, MINTED_STORAGE_USAGE};
+use crate::supply_caps::SupplyCaps;
+use crate::token_set_info::FeeAdjustments;
+use crate::utils;
+use crate::{events, Contract, FeeReceiver, SetInfo, TokenWithRatio, TokenWithRatioValid};
+
+/// The longest `token_id` of a hosted set
+const MAX_TOKEN_ID_LEN: usize = 64;
+
+pub type TokenId = String;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct HostedSet {
+    owner_id: AccountId,
+    token: FungibleToken,
+    set_info: SetInfo,
+    metadata: FungibleTokenMetadata,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MultiTokenHost {
+    sets: UnorderedMap<TokenId, HostedSet>,
+    /// The amount of each component backing the hosted sets together
+    reserves: LookupMap<AccountId, Balance>,
+}
+
+impl MultiTokenHost {
+    pub(crate) fn new() -> Self {
+        Self { sets: UnorderedMap::new(b"mts".to_vec()), reserves: LookupMap::new(b"mtr".to_vec()) }
+    }
+
+    /// The amount of `token_id` held as backing of the hosted sets
+    pub(crate) fn reserve_of(&self, token_id: &AccountId) -> Balance {
+        self.reserves.get(token_id).unwrap_or(0)
+    }
+
+    fn get_set(&self, token_id: &TokenId) -> HostedSet {
+        self.sets.get(token_id).unwrap_or_else(|| panic!("No set is hosted as {}", token_id))
+    }
+
+    /// The metadata of the hosted set `token_id`, `None` if there is no such set
+    pub(crate) fn metadata_of(&self, token_id: &TokenId) -> Option<FungibleTokenMetadata> {
+        self.sets.get(token_id).map(|set| set.metadata)
+    }
+
+    /// Track the reserves a hosted set gained or lost by minting or burning `amount` set tokens
+    fn update_reserves(&mut self, ratios: &[TokenWithRatio], amount: Balance, minted: bool) {
+        for ratio in ratios {
+            let reserve = self.reserve_of(&ratio.token_id);
+            let change = ratio.ratio as u128 * amount;
+            let reserve = if minted { reserve + change } else { reserve - change };
+            self.reserves.insert(&ratio.token_id, &reserve);
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct MtOwnerEvent<'a> {
+    owner_id: &'a AccountId,
+    token_ids: Vec<&'a TokenId>,
+    amounts: Vec<U128>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct MtTransferEvent<'a> {
+    old_owner_id: &'a AccountId,
+    new_owner_id: &'a AccountId,
+    token_ids: &'a [TokenId],
+    amounts: &'a [U128],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<&'a String>,
+}
+
+impl HostedSet {
+    /// Registration pays for the internal balances the set's components are credited to
+    fn assert_registered(&self, account_id: &AccountId, token_id: &TokenId) {
+        if !self.token.accounts.contains_key(account_id) {
+            panic!("The account {} is not registered with {}", account_id, token_id);
+        }
+    }
+
+    fn assert_owner(&self, token_id: &TokenId) {
+        if env::predecessor_account_id() != self.owner_id {
+            panic!("Only the owner of {} can call this method", token_id);
+        }
+    }
+}
+
+fn assert_valid_token_id(token_id: &TokenId) {
+    if token_id.is_empty() || token_id.len() > MAX_TOKEN_ID_LEN {
+        panic!("A token id has 1 to {} characters", MAX_TOKEN_ID_LEN);
+    }
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+    if !token_id.chars().all(valid) {
+        panic!("A token id only has lowercase letters, digits, '-' and '_'");
+    }
+}
+
+/// Only plain transfers are supported, hosted set tokens cannot be approved
+fn assert_no_approval<T>(approval: &Option<T>) {
+    if approval.is_some() {
+        panic!("Approvals are not supported");
+    }
+}
+
+/// Register `account_id` with `set` if it is not yet.
+///
+/// return the storage to reserve for the internal balances of the set's components
+fn register_with_set(set: &mut HostedSet, account_id: &AccountId) -> StorageUsage {
+    if set.token.accounts.contains_key(account_id) {
+        return 0;
+    }
+    set.token.internal_register_account(account_id);
+    set.set_info.ratios.len() * INTERNAL_BALANCE_STORAGE_USAGE + MINTED_STORAGE_USAGE
+}
+
+impl Contract {
+    /// Deposits of the hosted set's components are paid for by registering with it
+    pub(crate) fn assert_mt_depositable(
+        &self,
+        mt_token_id: &TokenId,
+        account_id: &AccountId,
+        token_id: &AccountId,
+    ) {
+        let set = self.multi_token.get_set(mt_token_id);
+        if !set.set_info.token_ids().contains(token_id) {
+            panic!("{} is not a component of {}", token_id, mt_token_id);
+        }
+        set.assert_registered(account_id, mt_token_id);
+    }
+
+    fn internal_mt_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_id: &TokenId,
+        amount: Balance,
+        memo: Option<String>,
+    ) {
+        let mut set = self.multi_token.get_set(token_id);
+        set.token.internal_transfer(sender_id, receiver_id, amount, memo);
+        self.multi_token.sets.insert(token_id, &set);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Host a new set under `token_id`. The platform pays for its storage.
+    #[payable]
+    pub fn mt_create_set(
+        &mut self,
+        token_id: TokenId,
+        owner_id: ValidAccountId,
+        metadata: FungibleTokenMetadata,
+        set_ratios: Vec<TokenWithRatioValid>,
+        owner_fee: U128,
+    ) {
+        let initial_storage_usage = env::storage_usage();
+        self.assert_platform();
+        assert_valid_token_id(&token_id);
+        if self.multi_token.sets.get(&token_id).is_some() {
+            panic!("A set is already hosted as {}", token_id);
+        }
+        metadata.assert_valid();
+        if set_ratios.iter().any(|ratio| ratio.is_set) {
+            panic!("Hosted sets cannot hold token sets");
+        }
+        let prefix = format!("mt:{}:", token_id).into_bytes();
+        let fee = FeeReceiver {
+            owner_fee: owner_fee.0,
+            platform_fee: self.set_info.fee.platform_fee,
+            platform_id: self.set_info.fee.platform_id.clone(),
+        };
+        let mut set = HostedSet {
+            owner_id: owner_id.into(),
+            token: FungibleToken::new([&prefix[..], b"a"].concat()),
+            set_info: SetInfo::with_prefix(&prefix, set_ratios, fee),
+            metadata,
+        };
+        // The fee receivers are paid in set tokens, so they have to be registered
+        let mut reserved = 0;
+        for account_id in [set.owner_id.clone(), set.set_info.fee.platform_id.clone()].iter() {
+            reserved += register_with_set(&mut set, account_id);
+        }
+        self.multi_token.sets.insert(&token_id, &set);
+        utils::charge_storage_with_reserve(initial_storage_usage, reserved);
+    }
+
+    /// Register `account_id`, or the caller, to hold the set tokens of `token_id` and deposit its
+    /// components. The caller pays for the storage.
+    #[payable]
+    pub fn mt_register(&mut self, token_id: TokenId, account_id: Option<ValidAccountId>) {
+        let initial_storage_usage = env::storage_usage();
+        let account_id: AccountId =
+            account_id.map(|id| id.into()).unwrap_or_else(env::predecessor_account_id);
+        let mut set = self.multi_token.get_set(&token_id);
+        let reserved = register_with_set(&mut set, &account_id);
+        if reserved > 0 {
+            self.multi_token.sets.insert(&token_id, &set);
+        }
+        utils::charge_storage_with_reserve(initial_storage_usage, reserved);
+    }
+
+    /// Wrap the caller's internal balances into set tokens of the hosted set `token_id`. An
+    /// unregistered caller is registered and pays the storage from the attached deposit.
+    ///
+    /// return the amount minted, fees included
+    #[payable]
+    pub fn mt_wrap(&mut self, token_id: TokenId, amount: Option<U128>) -> U128 {
+        utils::assert_at_least_1_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let caller = env::predecessor_account_id();
+        let mut set = self.multi_token.get_set(&token_id);
+        self.assert_components_allowed(&set.set_info.token_ids());
+        self.assert_can_wrap_into(&set.set_info, &caller);
+        let reserved = register_with_set(&mut set, &caller);
+        let mut receivers = vec![caller.clone()];
+        for account_id in [set.owner_id.clone(), set.set_info.fee.platform_id.clone()].iter() {
+            if !receivers.contains(account_id) {
+                receivers.push(account_id.clone());
+            }
+        }
+        let balances_before: Vec<Balance> =
+            receivers.iter().map(|id| set.token.internal_unwrap_balance_of(id)).collect();
+
+        let (amount, _) = set.set_info.wrap(
+            &set.owner_id,
+            &caller,
+            &mut set.token,
+            &mut self.balances,
+            amount.map(|amount| amount.0),
+            FeeAdjustments::default(),
+        );
+        self.multi_token.update_reserves(&set.set_info.get_ratios(), amount, true);
+
+        for (account_id, before) in receivers.iter().zip(balances_before) {
+            let minted = set.token.internal_unwrap_balance_of(account_id) - before;
+            if minted > 0 {
+                let event = MtOwnerEvent {
+                    owner_id: account_id,
+                    token_ids: vec![&token_id],
+                    amounts: vec![minted.into()],
+                };
+                events::emit_nep245("mt_mint", &[event]);
+            }
+        }
+        self.multi_token.sets.insert(&token_id, &set);
+        utils::charge_storage_with_reserve(initial_storage_usage, reserved);
+        amount.into()
+    }
+
+    /// Burn set tokens of the hosted set `token_id` and credit the caller, who has to be
+    /// registered with it, the underlying tokens. Unwraps the whole balance if no amount is given.
+    #[payable]
+    pub fn mt_unwrap(&mut self, token_id: TokenId, amount: Option<U128>) -> U128 {
+        utils::assert_1_yocto();
+        let caller = env::predecessor_account_id();
+        let mut set = self.multi_token.get_set(&token_id);
+        set.assert_registered(&caller, &token_id);
+        let amount = set.set_info.burn(&mut set.token, &caller, amount.map(|amount| amount.0));
+        set.set_info.on_burn(&mut self.balances, caller.clone(), amount);
+        self.multi_token.update_reserves(&set.set_info.get_ratios(), amount, false);
+        self.multi_token.sets.insert(&token_id, &set);
+        let event = MtOwnerEvent {
+            owner_id: &caller,
+            token_ids: vec![&token_id],
+            amounts: vec![amount.into()],
+        };
+        events::emit_nep245("mt_burn", &[event]);
+        amount.into()
+    }
+
+    /// Lower the owner fee of the hosted set `token_id`, only its owner can
+    #[payable]
+    pub fn mt_set_owner_fee(&mut self, token_id: TokenId, owner_fee: U128) {
+        utils::assert_1_yocto();
+        let mut set = self.multi_token.get_set(&token_id);
+        set.assert_owner(&token_id);
+        set.set_info.change_owner_fee(owner_fee.0);
+        self.multi_token.sets.insert(&token_id, &set);
+    }
+
+    /// Add or lower the supply caps of the hosted set `token_id`, only its owner can. See
+    /// `set_supply_caps` for their meaning.
+    #[payable]
+    pub fn mt_set_supply_caps(
+        &mut self,
+        token_id: TokenId,
+        max_total_supply: Option<U128>,
+        max_account_mint: Option<U128>,
+    ) {
+        utils::assert_1_yocto();
+        let mut set = self.multi_token.get_set(&token_id);
+        set.assert_owner(&token_id);
+        set.set_info.caps.tighten(SupplyCaps::from((max_total_supply, max_account_mint)));
+        self.multi_token.sets.insert(&token_id, &set);
+    }
+
+    #[payable]
+    pub fn mt_transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        amount: U128,
+        approval: Option<(AccountId, u64)>,
+        memo: Option<String>,
+    ) {
+        utils::assert_1_yocto();
+        assert_no_approval(&approval);
+        let sender_id = env::predecessor_account_id();
+        let receiver_id: AccountId = receiver_id.into();
+        self.internal_mt_transfer(&sender_id, &receiver_id, &token_id, amount.0, memo.clone());
+        events::emit_nep245(
+            "mt_transfer",
+            &[MtTransferEvent {
+                old_owner_id: &sender_id,
+                new_owner_id: &receiver_id,
+                token_ids: &[token_id],
+                amounts: &[amount],
+                memo: memo.as_ref(),
+            }],
+        );
+    }
+
+    #[payable]
+    pub fn mt_batch_transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_ids: Vec<TokenId>,
+        amounts: Vec<U128>,
+        approvals: Option<Vec<Option<(AccountId, u64)>>>,
+        memo: Option<String>,
+    ) {
+        utils::assert_1_yocto();
+        if token_ids.len() != amounts.len() {
+            panic!("Expected an amount for each token id");
+        }
+        if let Some(approvals) = &approvals {
+            approvals.iter().for_each(assert_no_approval);
+        }
+        let sender_id = env::predecessor_account_id();
+        for (token_id, amount) in token_ids.iter().zip(amounts.iter()) {
+            self.internal_mt_transfer(&sender_id, receiver_id.as_ref(), token_id, amount.0, None);
+        }
+        events::emit_nep245(
+            "mt_transfer",
+            &[MtTransferEvent {
+                old_owner_id: &sender_id,
+                new_owner_id: receiver_id.as_ref(),
+                token_ids: &token_ids,
+                amounts: &amounts,
+                memo: memo.as_ref(),
+            }],
+        );
+    }
+
+    pub fn mt_balance_of(&self, account_id: ValidAccountId, token_id: TokenId) -> U128 {
+        let set = self.multi_token.get_set(&token_id);
+        set.token.accounts.get(account_id.as_ref()).unwrap_or(0).into()
+    }
+
+    pub fn mt_batch_balance_of(
+        &self,
+        account_id: ValidAccountId,
+        token_ids: Vec<TokenId>,
+    ) -> Vec<U128> {
+        token_ids
+            .into_iter()
+            .map(|token_id| self.mt_balance_of(account_id.clone(), token_id))
+            .collect()
+    }
+
+    /// The total supply of the hosted set `token_id`, `None` if there is no such set
+    pub fn mt_supply(&self, token_id: TokenId) -> Option<U128> {
+        self.multi_token.sets.get(&token_id).map(|set| set.token.total_supply.into())
+    }
+
+    pub fn mt_get_set_ratios(&self, token_id: TokenId) -> Vec<TokenWithRatio> {
+        self.multi_token.get_set(&token_id).set_info.get_ratios()
+    }
+
+    pub fn mt_get_set_owner(&self, token_id: TokenId) -> AccountId {
+        self.multi_token.get_set(&token_id).owner_id
+    }
+
+    /// The token ids of the hosted sets, paginated
+    pub fn mt_token_ids(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<TokenId> {
+        let keys = self.multi_token.sets.keys_as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(keys.len());
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|i| keys.get(i).unwrap())
+            .collect()
+    }
+}
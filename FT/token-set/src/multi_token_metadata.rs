@@ -0,0 +1,138 @@
+//! The NEP-245 metadata of the hosted sets.
+//!
+//! Every hosted set is its own base: the base metadata carries what fungible token metadata
+//! would, keyed by the set's `token_id`, and the token metadata only repeats its name and
+//! reference. The contract metadata names the host after its own set.
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::multi_token::TokenId;
+use crate::Contract;
+
+const MT_METADATA_SPEC: &str = "mt-1.0.0";
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MTContractMetadata {
+    pub spec: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MTBaseTokenMetadata {
+    pub name: String,
+    pub id: String,
+    pub symbol: Option<String>,
+    pub icon: Option<String>,
+    pub decimals: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub copies: Option<u64>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MTTokenMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<Base64VecU8>,
+    pub issued_at: Option<String>,
+    pub expires_at: Option<String>,
+    pub starts_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub extra: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MTTokenMetadataAll {
+    pub base: MTBaseTokenMetadata,
+    pub token: MTTokenMetadata,
+}
+
+fn base_metadata(token_id: &TokenId, metadata: &FungibleTokenMetadata) -> MTBaseTokenMetadata {
+    MTBaseTokenMetadata {
+        name: metadata.name.clone(),
+        id: token_id.clone(),
+        symbol: Some(metadata.symbol.clone()),
+        icon: metadata.icon.clone(),
+        decimals: Some(metadata.decimals.to_string()),
+        base_uri: None,
+        reference: metadata.reference.clone(),
+        copies: None,
+        reference_hash: metadata.reference_hash.clone(),
+    }
+}
+
+fn token_metadata(metadata: &FungibleTokenMetadata) -> MTTokenMetadata {
+    MTTokenMetadata {
+        title: Some(metadata.name.clone()),
+        description: None,
+        media: None,
+        media_hash: None,
+        issued_at: None,
+        expires_at: None,
+        starts_at: None,
+        updated_at: None,
+        extra: None,
+        reference: metadata.reference.clone(),
+        reference_hash: metadata.reference_hash.clone(),
+    }
+}
+
+impl Contract {
+    /// The metadata of each of `token_ids` mapped by `f`, `None` for token ids which are not hosted
+    fn map_mt_metadata<T>(
+        &self,
+        token_ids: &[TokenId],
+        f: impl Fn(&TokenId, &FungibleTokenMetadata) -> T,
+    ) -> Vec<Option<T>> {
+        token_ids
+            .iter()
+            .map(|token_id| {
+                self.multi_token.metadata_of(token_id).map(|metadata| f(token_id, &metadata))
+            })
+            .collect()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn mt_metadata_contract(&self) -> MTContractMetadata {
+        MTContractMetadata {
+            spec: MT_METADATA_SPEC.to_string(),
+            name: self.metadata.get().unwrap().name,
+        }
+    }
+
+    pub fn mt_metadata_token_all(
+        &self,
+        token_ids: Vec<TokenId>,
+    ) -> Vec<Option<MTTokenMetadataAll>> {
+        self.map_mt_metadata(&token_ids, |token_id, metadata| MTTokenMetadataAll {
+            base: base_metadata(token_id, metadata),
+            token: token_metadata(metadata),
+        })
+    }
+
+    pub fn mt_metadata_token_by_token_id(
+        &self,
+        token_ids: Vec<TokenId>,
+    ) -> Vec<Option<MTTokenMetadata>> {
+        self.map_mt_metadata(&token_ids, |_, metadata| token_metadata(metadata))
+    }
+
+    pub fn mt_metadata_base_by_token_id(
+        &self,
+        token_ids: Vec<TokenId>,
+    ) -> Vec<Option<MTBaseTokenMetadata>> {
+        self.map_mt_metadata(&token_ids, base_metadata)
+    }
+}
@@ -0,0 +1,199 @@
+//! Native NEAR as a set component, held as wNEAR.
+//!
+//! The owner names the component which is a wNEAR contract (e.g. `wrap.near`). NEAR attached to
+//! `wrap` is then deposited into it with `near_deposit` and credited to the caller's internal
+//! balance before wrapping, and `unwrap` can `near_withdraw` the caller's share of it and send it
+//! as native NEAR. Only the NEAR the wrap needs on top of the caller's wNEAR internal balance is
+//! deposited: the backing of `amount`, or without an amount of as many set tokens as the other
+//! components' internal balances allow. The rest is refunded.
+//!
+//! The wrap itself runs in its own receipt after the deposit: if it fails, e.g. because the
+//! other components are missing, the caller keeps the wNEAR as internal balance.
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, Promise, PromiseResult,
+};
+
+use crate::utils;
+use crate::Contract;
+
+const NO_DEPOSIT: Balance = 0;
+const ONE_YOCTO: Balance = 1;
+const GAS_FOR_NEAR_DEPOSIT: Gas = 10_000_000_000_000;
+const GAS_FOR_NEAR_WITHDRAW: Gas = 10_000_000_000_000;
+const GAS_FOR_WRAP_DEPOSITED: Gas = 60_000_000_000_000;
+const GAS_FOR_ON_NEAR_DEPOSITED: Gas = GAS_FOR_WRAP_DEPOSITED + 20_000_000_000_000;
+const GAS_FOR_ON_NEAR_WITHDRAWN: Gas = 10_000_000_000_000;
+
+#[ext_contract(ext_wnear)]
+pub trait WrappedNear {
+    fn near_deposit(&mut self);
+
+    fn near_withdraw(&mut self, amount: U128);
+}
+
+#[ext_contract(ext_self)]
+pub trait NativeCallbacks {
+    fn on_near_deposited(
+        &mut self,
+        account_id: AccountId,
+        wnear_id: AccountId,
+        deposit: U128,
+        amount: Option<U128>,
+        referrer_id: Option<AccountId>,
+    ) -> Promise;
+
+    fn wrap_deposited(
+        &mut self,
+        account_id: AccountId,
+        amount: Option<U128>,
+        referrer_id: Option<AccountId>,
+    );
+
+    fn on_near_withdrawn(&mut self, account_id: AccountId, wnear_id: AccountId, amount: U128);
+}
+
+impl Contract {
+    /// The wNEAR contract, as long as it is still a component
+    pub(crate) fn wnear_component(&self) -> Option<AccountId> {
+        self.wnear_id.clone().filter(|wnear_id| self.set_info.token_ids().contains(wnear_id))
+    }
+
+    /// The NEAR `account_id` has to deposit as wNEAR to wrap `amount`, or as much as its other
+    /// internal balances allow if `None`. A set without other components takes any amount.
+    pub(crate) fn near_to_wrap(&self, account_id: &AccountId, amount: Option<u128>) -> Balance {
+        let wnear_id = self.wnear_component().expect("The set has no wNEAR component");
+        let ratio = self.set_info.get_ratios().into_iter().find(|r| r.token_id == wnear_id);
+        let ratio = ratio.unwrap();
+        let amount = amount.unwrap_or_else(|| {
+            self.set_info.max_amount_except(&self.balances, account_id, Some(&wnear_id))
+        });
+        if amount.checked_mul(ratio.ratio as u128).is_none() {
+            return Balance::MAX;
+        }
+        let backing = self.set_info.backing_of(&ratio, amount, true);
+        backing.saturating_sub(self.balances.get_ft_balance(account_id, &wnear_id))
+    }
+
+    /// Deposit `deposit` yoctoNEAR as wNEAR for `account_id`, then wrap its internal balances
+    pub(crate) fn deposit_near_and_wrap(
+        &mut self,
+        account_id: AccountId,
+        deposit: Balance,
+        amount: Option<u128>,
+        referrer_id: Option<AccountId>,
+    ) -> Promise {
+        let wnear_id = self.wnear_component().expect("The set has no wNEAR component");
+        ext_wnear::near_deposit(&wnear_id, deposit, GAS_FOR_NEAR_DEPOSIT).then(
+            ext_self::on_near_deposited(
+                account_id,
+                wnear_id.clone(),
+                deposit.into(),
+                amount.map(U128),
+                referrer_id,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_ON_NEAR_DEPOSITED,
+            ),
+        )
+    }
+
+    /// Withdraw the wNEAR which burning `amount` set tokens credited to `account_id` and send it
+    /// as native NEAR
+    pub(crate) fn withdraw_near(&mut self, account_id: AccountId, amount: Balance) -> Promise {
+        let wnear_id = self.wnear_component().expect("The set has no wNEAR component");
+        let ratio = self.set_info.get_ratios().into_iter().find(|r| r.token_id == wnear_id);
+        let near_amount = self.set_info.backing_of(&ratio.unwrap(), amount, false);
+        self.balances.subtract_balance(&account_id, &wnear_id, near_amount);
+        ext_wnear::near_withdraw(near_amount.into(), &wnear_id, ONE_YOCTO, GAS_FOR_NEAR_WITHDRAW)
+            .then(ext_self::on_near_withdrawn(
+                account_id,
+                wnear_id,
+                near_amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_ON_NEAR_WITHDRAWN,
+            ))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Name the component native NEAR attached to `wrap` is deposited into, or none
+    #[payable]
+    pub fn set_wnear_id(&mut self, wnear_id: Option<ValidAccountId>) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if let Some(wnear_id) = &wnear_id {
+            let component = self
+                .set_info
+                .get_ratios()
+                .into_iter()
+                .find(|r| &r.token_id == wnear_id.as_ref())
+                .unwrap_or_else(|| panic!("{} is not in the set", wnear_id));
+            if component.is_set {
+                panic!("{} is a token set, not wNEAR", wnear_id);
+            }
+        }
+        self.wnear_id = wnear_id.map(|id| id.into());
+    }
+
+    pub fn get_wnear_id(&self) -> Option<AccountId> {
+        self.wnear_component()
+    }
+
+    /// Credit the deposited wNEAR and wrap in a separate receipt, so that the credit stays if the
+    /// wrap fails. Refunds the NEAR if the deposit failed.
+    #[private]
+    pub fn on_near_deposited(
+        &mut self,
+        account_id: AccountId,
+        wnear_id: AccountId,
+        deposit: U128,
+        amount: Option<U128>,
+        referrer_id: Option<AccountId>,
+    ) -> Promise {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                self.balances.increase_balance(&account_id, &wnear_id, deposit.0);
+                ext_self::wrap_deposited(
+                    account_id,
+                    amount,
+                    referrer_id,
+                    &env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_WRAP_DEPOSITED,
+                )
+            }
+            _ => {
+                log!("Depositing NEAR failed, refunding {} to @{}", deposit.0, account_id);
+                Promise::new(account_id).transfer(deposit.0)
+            }
+        }
+    }
+
+    #[private]
+    pub fn wrap_deposited(
+        &mut self,
+        account_id: AccountId,
+        amount: Option<U128>,
+        referrer_id: Option<AccountId>,
+    ) {
+        self.assert_can_wrap(&account_id);
+        self.internal_wrap(&account_id, amount.map(|amount| amount.0), referrer_id.as_ref());
+    }
+
+    /// Send the withdrawn NEAR, or credit the wNEAR back if the withdrawal failed
+    #[private]
+    pub fn on_near_withdrawn(&mut self, account_id: AccountId, wnear_id: AccountId, amount: U128) {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                Promise::new(account_id).transfer(amount.0);
+            }
+            _ => {
+                log!("Withdrawing NEAR failed, crediting the wNEAR back to @{}", account_id);
+                self.balances.increase_balance(&account_id, &wnear_id, amount.0);
+            }
+        }
+    }
+}
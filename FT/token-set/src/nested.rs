@@ -0,0 +1,178 @@
+//! Token sets whose components are token sets themselves.
+//!
+//! A component marked with `is_set` is held like any other token, but quoting and unwrapping
+//! can drill down through it to the base tokens.
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, PromiseOrValue, PromiseResult,
+};
+
+use crate::utils;
+use crate::Contract;
+
+const NO_DEPOSIT: Balance = 0;
+const ONE_YOCTO: Balance = 1;
+const GAS_FOR_NESTED_CALL: Gas = 40_000_000_000_000;
+const GAS_FOR_NESTED_CALLBACK: Gas = 10_000_000_000_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenAmount {
+    pub token_id: AccountId,
+    pub amount: U128,
+}
+
+#[ext_contract(ext_token_set)]
+pub trait TokenSet {
+    fn quote_required_underlying(&self, amount: U128) -> Vec<TokenAmount>;
+
+    fn unwrap_nested(&mut self, amount: Option<u128>, receiver_id: Option<AccountId>) -> U128;
+}
+
+#[ext_contract(ext_self)]
+pub trait NestedCallbacks {
+    fn on_quote_nested(&self, base: Vec<TokenAmount>) -> Vec<TokenAmount>;
+
+    fn on_unwrap_nested(
+        &mut self,
+        receiver_id: AccountId,
+        nested: Vec<TokenAmount>,
+        amount: U128,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl Contract {
+    /// The amount of each base token needed to mint `amount` set tokens, before fees. Components
+    /// which are token sets are quoted recursively, so this has to be called as a transaction
+    /// when the set is nested.
+    pub fn quote_required_underlying(&self, amount: U128) -> PromiseOrValue<Vec<TokenAmount>> {
+        let (base, nested): (Vec<_>, Vec<_>) =
+            self.set_info.get_ratios().into_iter().partition(|token| !token.is_set);
+        let base: Vec<TokenAmount> = base
+            .into_iter()
+            .map(|token| TokenAmount {
+                amount: self.set_info.backing_of(&token, amount.0, true).into(),
+                token_id: token.token_id,
+            })
+            .collect();
+        if nested.is_empty() {
+            return PromiseOrValue::Value(merge_amounts(base));
+        }
+        let quotes = utils::join_promises(nested.into_iter().map(|token| {
+                ext_token_set::quote_required_underlying(
+                    self.set_info.backing_of(&token, amount.0, true).into(),
+                    &token.token_id,
+                    NO_DEPOSIT,
+                    GAS_FOR_NESTED_CALL,
+                )
+            }));
+        quotes
+            .then(ext_self::on_quote_nested(
+                base,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_NESTED_CALLBACK,
+            ))
+            .into()
+    }
+
+    #[private]
+    pub fn on_quote_nested(&self, base: Vec<TokenAmount>) -> Vec<TokenAmount> {
+        let mut amounts = base;
+        for i in 0..env::promise_results_count() {
+            match env::promise_result(i) {
+                PromiseResult::Successful(value) => amounts.extend(
+                    serde_json::from_slice::<Vec<TokenAmount>>(&value)
+                        .expect("Failed to parse the nested set's quote"),
+                ),
+                _ => panic!("Failed to quote the underlying of a nested set"),
+            }
+        }
+        merge_amounts(amounts)
+    }
+
+    /// Burn set tokens of the caller and credit `receiver_id` (the caller by default) with the
+    /// underlying tokens. Components which are token sets are unwrapped in turn, so the receiver
+    /// ends up with internal balances of base tokens in the nested sets. If unwrapping a nested
+    /// set fails, its tokens are credited in this set instead.
+    ///
+    /// return the amount of set tokens burned
+    #[payable]
+    pub fn unwrap_nested(
+        &mut self,
+        amount: Option<u128>,
+        receiver_id: Option<ValidAccountId>,
+    ) -> PromiseOrValue<U128> {
+        utils::assert_1_yocto();
+        self.rebalancer.assert_not_pending();
+        if self.set_info.nfts.is_fixed_supply() {
+            panic!("The NFTs are only redeemed by burning the entire supply");
+        }
+        let caller = env::predecessor_account_id();
+        let receiver_id: AccountId = receiver_id.map(|id| id.into()).unwrap_or(caller.clone());
+        self.assert_registered(&receiver_id);
+        let amount = self.checkpointed(&[caller.clone()], |this| {
+            this.set_info.burn(&mut this.token, &caller, amount)
+        });
+        let nested = self.set_info.on_burn_nested(&mut self.balances, &receiver_id, amount);
+        if nested.is_empty() {
+            return PromiseOrValue::Value(amount.into());
+        }
+
+        let nested: Vec<TokenAmount> = nested
+            .into_iter()
+            .map(|(token_id, amount)| TokenAmount { token_id, amount: amount.into() })
+            .collect();
+        let unwraps = utils::join_promises(nested.iter().map(|token| {
+            ext_token_set::unwrap_nested(
+                Some(token.amount.0),
+                Some(receiver_id.clone()),
+                &token.token_id,
+                ONE_YOCTO,
+                GAS_FOR_NESTED_CALL,
+            )
+        }));
+        unwraps
+            .then(ext_self::on_unwrap_nested(
+                receiver_id,
+                nested,
+                amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_NESTED_CALLBACK,
+            ))
+            .into()
+    }
+
+    #[private]
+    pub fn on_unwrap_nested(
+        &mut self,
+        receiver_id: AccountId,
+        nested: Vec<TokenAmount>,
+        amount: U128,
+    ) -> U128 {
+        for (i, token) in nested.iter().enumerate() {
+            if let PromiseResult::Successful(_) = env::promise_result(i as u64) {
+                continue;
+            }
+            log!("Failed to unwrap {} {}, crediting it as is", token.amount.0, token.token_id);
+            self.balances.increase_balance(&receiver_id, &token.token_id, token.amount.0);
+        }
+        amount
+    }
+}
+
+/// Sum up the amounts of the same token, keeping the order in which tokens first appear
+fn merge_amounts(amounts: Vec<TokenAmount>) -> Vec<TokenAmount> {
+    let mut merged: Vec<TokenAmount> = vec![];
+    for token in amounts {
+        match merged.iter_mut().find(|m| m.token_id == token.token_id) {
+            Some(m) => m.amount = (m.amount.0 + token.amount.0).into(),
+            None => merged.push(token),
+        }
+    }
+    merged
+}
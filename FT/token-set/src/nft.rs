@@ -0,0 +1,267 @@
+//! NEP-171 non-fungible tokens as components, for baskets fractionalizing specific NFTs.
+//!
+//! The owner lists the NFTs the set holds and the fixed supply they back while nothing is
+//! minted. The NFTs are deposited with `nft_transfer_call` by a single depositor, and the
+//! deposit of the last one issues the whole supply to the depositor, who also pays the fungible
+//! components of it from their internal balances. The supply is issued without fees, so that the
+//! depositor holds all of it. Set tokens cannot be wrapped otherwise, and the NFTs are only
+//! redeemed by burning the entire supply at once.
+//!
+//! If issuing fails, e.g. because the fungible components are missing, the last NFT is sent
+//! back and the depositor can take back the others with `withdraw_nfts`.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::Vector;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult,
+};
+
+use crate::external::ext_non_fungible_token;
+use crate::rebalance::WEIGHT_DENOMINATOR;
+use crate::token_set_info::FeeAdjustments;
+use crate::utils;
+use crate::Contract;
+
+const NO_DEPOSIT: Balance = 0;
+const ONE_YOCTO: Balance = 1;
+const GAS_FOR_NFT_TRANSFER: Gas = 15_000_000_000_000;
+const GAS_FOR_ON_NFT_RETURNED: Gas = 10_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftComponent {
+    /// The NEP-171 contract
+    pub contract_id: AccountId,
+    pub token_id: String,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct HeldNft {
+    component: NftComponent,
+    held: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftComponentView {
+    pub contract_id: AccountId,
+    pub token_id: String,
+    /// Whether the set holds the NFT
+    pub held: bool,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct NftBacking {
+    nfts: Vector<HeldNft>,
+    /// The supply issued once every NFT is held
+    supply: Balance,
+    /// The account depositing the NFTs, which the supply is issued to
+    depositor: Option<AccountId>,
+}
+
+impl NftBacking {
+    pub(crate) fn new(prefix: &[u8]) -> Self {
+        Self { nfts: Vector::new([prefix, b"nft"].concat()), supply: 0, depositor: None }
+    }
+
+    /// Whether the set has NFT components, so that its supply is fixed
+    pub(crate) fn is_fixed_supply(&self) -> bool {
+        !self.nfts.is_empty()
+    }
+
+    fn position(&self, component: &NftComponent) -> Option<u64> {
+        (0..self.nfts.len()).find(|&i| &self.nfts.get(i).unwrap().component == component)
+    }
+
+    fn set_held(&mut self, index: u64, held: bool) {
+        let nft = self.nfts.get(index).unwrap();
+        self.nfts.replace(index, &HeldNft { held, ..nft });
+    }
+
+    fn all_held(&self) -> bool {
+        self.nfts.iter().all(|nft| nft.held)
+    }
+
+    fn any_held(&self) -> bool {
+        self.nfts.iter().any(|nft| nft.held)
+    }
+}
+
+#[ext_contract(ext_self)]
+pub trait NftCallbacks {
+    fn on_nft_returned(&mut self, receiver_id: AccountId, index: u64);
+}
+
+impl Contract {
+    /// Check that `amount` set tokens of `account_id`, all of them if `None`, are the entire supply
+    pub(crate) fn assert_full_redemption(&self, account_id: &AccountId, amount: Option<Balance>) {
+        let amount = amount.unwrap_or_else(|| self.token.accounts.get(account_id).unwrap_or(0));
+        if amount != self.token.total_supply || self.unclaimed_supply() > 0 {
+            panic!("The NFTs are only redeemed by burning the entire supply");
+        }
+    }
+
+    /// Send every held NFT to `receiver_id`. The NFTs whose transfer fails stay held and can be
+    /// withdrawn by `receiver_id`.
+    pub(crate) fn return_nfts(&mut self, receiver_id: &AccountId) -> Promise {
+        let held: Vec<u64> = (0..self.set_info.nfts.nfts.len())
+            .filter(|&i| self.set_info.nfts.nfts.get(i).unwrap().held)
+            .collect();
+        if held.is_empty() {
+            panic!("No NFT is held");
+        }
+        self.set_info.nfts.depositor = None;
+        utils::join_promises(held.into_iter().map(|i| {
+            self.set_info.nfts.set_held(i, false);
+            let component = self.set_info.nfts.nfts.get(i).unwrap().component;
+            ext_non_fungible_token::nft_transfer(
+                receiver_id.clone(),
+                component.token_id,
+                None,
+                None,
+                &component.contract_id,
+                ONE_YOCTO,
+                GAS_FOR_NFT_TRANSFER,
+            )
+            .then(ext_self::on_nft_returned(
+                receiver_id.clone(),
+                i,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_ON_NFT_RETURNED,
+            ))
+        }))
+    }
+
+    /// Mint the fixed supply to the depositor of the NFTs. No fees are charged, as they would
+    /// leave the depositor short of the entire supply the NFTs are redeemed with.
+    fn issue_nft_supply(&mut self, depositor: &AccountId) {
+        self.assert_components_verified();
+        self.rebalancer.assert_not_pending();
+        self.assert_can_mint(depositor);
+        self.assert_registered(depositor);
+        let supply = self.set_info.nfts.supply;
+        let adjustments = FeeAdjustments { referral_bps: 0, discount_bps: WEIGHT_DENOMINATOR };
+        self.checkpointed(&[depositor.clone()], |this| {
+            let owner_id = &this.owner_id;
+            this.set_info.wrap(
+                owner_id,
+                depositor,
+                &mut this.token,
+                &mut this.balances,
+                Some(supply),
+                adjustments,
+            )
+        });
+        log!("Issued {} set tokens to @{}", supply, depositor);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// List the NFTs backing the set and the supply they back, or none. Only while nothing is
+    /// minted and no NFT is held.
+    #[payable]
+    pub fn set_nft_components(&mut self, nfts: Vec<NftComponent>, supply: U128) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if self.token.total_supply > 0 || self.unclaimed_supply() > 0 {
+            panic!("The NFT components can only be changed while nothing is minted");
+        }
+        if self.set_info.nfts.any_held() {
+            panic!("The NFT components can only be changed while no NFT is held");
+        }
+        if !nfts.is_empty() && supply.0 == 0 {
+            panic!("Expected a positive supply");
+        }
+        self.set_info.nfts.nfts.clear();
+        for component in nfts {
+            if self.set_info.nfts.position(&component).is_some() {
+                panic!("Each NFT must be unique");
+            }
+            self.set_info.nfts.nfts.push(&HeldNft { component, held: false });
+        }
+        self.set_info.nfts.supply = supply.0;
+        self.set_info.nfts.depositor = None;
+    }
+
+    pub fn get_nft_components(&self) -> Vec<NftComponentView> {
+        self.set_info
+            .nfts
+            .nfts
+            .iter()
+            .map(|nft| NftComponentView {
+                contract_id: nft.component.contract_id,
+                token_id: nft.component.token_id,
+                held: nft.held,
+            })
+            .collect()
+    }
+
+    pub fn get_nft_supply(&self) -> U128 {
+        self.set_info.nfts.supply.into()
+    }
+
+    /// NEP-171 receiver. Keeps the listed NFTs sent by the depositor and returns anything else.
+    /// The last NFT issues the supply.
+    ///
+    /// return whether the NFT is returned
+    pub fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: String,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        let _ = (sender_id, msg);
+        let component = NftComponent { contract_id: env::predecessor_account_id(), token_id };
+        let index = match self.set_info.nfts.position(&component) {
+            Some(index) if !self.set_info.nfts.nfts.get(index).unwrap().held => index,
+            _ => {
+                log!("{}:{} is not an NFT component", component.contract_id, component.token_id);
+                return PromiseOrValue::Value(true);
+            }
+        };
+        if self.token.total_supply > 0 {
+            log!("The supply is issued already");
+            return PromiseOrValue::Value(true);
+        }
+        match &self.set_info.nfts.depositor {
+            Some(depositor) if depositor != &previous_owner_id => {
+                log!("The NFTs are deposited by @{}", depositor);
+                return PromiseOrValue::Value(true);
+            }
+            _ => self.set_info.nfts.depositor = Some(previous_owner_id.clone()),
+        }
+        self.set_info.nfts.set_held(index, true);
+        if self.set_info.nfts.all_held() {
+            self.issue_nft_supply(&previous_owner_id);
+        }
+        PromiseOrValue::Value(false)
+    }
+
+    /// Take back the NFTs deposited so far, before the supply is issued
+    pub fn withdraw_nfts(&mut self) -> Promise {
+        let caller = env::predecessor_account_id();
+        if self.set_info.nfts.depositor.as_ref() != Some(&caller) {
+            panic!("Only the depositor can withdraw the NFTs");
+        }
+        if self.token.total_supply > 0 {
+            panic!("The NFTs are only redeemed by burning the entire supply");
+        }
+        self.return_nfts(&caller)
+    }
+
+    /// Hold on to an NFT whose transfer failed, leaving it to `receiver_id` to withdraw
+    #[private]
+    pub fn on_nft_returned(&mut self, receiver_id: AccountId, index: u64) {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!("Returning the NFT failed, @{} can withdraw it", receiver_id);
+        self.set_info.nfts.set_held(index, true);
+        self.set_info.nfts.depositor = Some(receiver_id);
+    }
+}
@@ -0,0 +1,596 @@
+//! Oracle-driven rebalancing of the set's composition towards owner defined target weights.
+//!
+//! The owner sets a target weight (in basis points) for every component. The owner or a holder of
+//! `Role::Rebalancer` can then call `rebalance`, which fetches the components' prices from the
+//! oracle and recomputes the ratios so that the value of one set token stays the same while each
+//! component's share of that value matches its target weight. A rebalance is only done once some
+//! component drifted at least the owner's deviation threshold from its target weight.
+//!
+//! Wrapping and unwrapping are paused while a rebalance is pending, which is why starting one is
+//! restricted. A rebalance left pending for longer than `PENDING_REBALANCE_TTL_NS` can be
+//! cancelled by anyone, see `cancel_rebalance`.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Gas, Promise, PromiseResult};
+
+use crate::roles::Role;
+use crate::utils::{self, U256};
+use crate::{events, Contract, TokenWithRatio};
+
+/// Target weights are expressed in basis points and have to sum up to this value
+pub const WEIGHT_DENOMINATOR: u32 = 10_000;
+
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_GET_PRICE_DATA: Gas = 10_000_000_000_000;
+const GAS_FOR_ON_REBALANCE_PRICES: Gas = 30_000_000_000_000;
+/// A day
+pub(crate) const PENDING_REBALANCE_TTL_NS: u64 = 86_400_000_000_000;
+/// A year
+const MAX_COOLDOWN_SEC: u64 = 31_536_000;
+/// Prices with more decimals could overflow the computations once normalized
+const MAX_PRICE_DECIMALS: u8 = 24;
+
+/// A price as reported by the oracle: the value of one indivisible unit of the asset is
+/// `multiplier / 10^decimals`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Price {
+    pub multiplier: U128,
+    pub decimals: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetOptionalPrice {
+    pub asset_id: AccountId,
+    pub price: Option<Price>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceData {
+    pub timestamp: U64,
+    pub recency_duration_sec: u32,
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+#[ext_contract(ext_price_oracle)]
+pub trait PriceOracle {
+    fn get_price_data(&self, asset_ids: Option<Vec<AccountId>>) -> PriceData;
+}
+
+#[ext_contract(ext_self)]
+pub trait RebalanceCallbacks {
+    fn on_rebalance_prices(&mut self) -> Vec<TokenWithRatio>;
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Rebalancer {
+    /// The price oracle used to value the components
+    pub(crate) oracle_id: Option<AccountId>,
+    /// Target weight of each component in basis points, in the same order as `SetInfo::ratios`
+    pub(crate) target_weights: Vec<u32>,
+    /// Rebalancing is refused unless some component is at least this far from its target weight
+    pub(crate) deviation_threshold_bps: u32,
+    /// Minimum time between two rebalances
+    pub(crate) cooldown_ns: u64,
+    pub(crate) last_rebalance_ns: u64,
+    /// The number of rebalances done so far
+    pub(crate) nonce: u64,
+    /// The AMM used to swap the reserves when rebalancing
+    pub(crate) amm_id: Option<AccountId>,
+    /// How far below the oracle price a swap's `min_amount_out` may be
+    pub(crate) max_slippage_bps: u32,
+    pub(crate) pending: Option<PendingRebalance>,
+}
+
+/// A rebalance whose ratios are known but whose reserves have not been swapped yet
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PendingRebalance {
+    pub(crate) target_ratios: Vec<u32>,
+    /// The oracle prices the target ratios were computed with
+    pub(crate) prices: Vec<Price>,
+    /// The set's total supply, which is frozen while the rebalance is pending
+    pub(crate) supply: Balance,
+    /// Amount of each component sold so far
+    pub(crate) sold: Vec<Balance>,
+    /// Minimum amount of each component bought so far
+    pub(crate) bought: Vec<Balance>,
+    /// Whether swaps are currently being executed
+    pub(crate) in_flight: bool,
+    /// When the rebalance was computed
+    pub(crate) created_ns: u64,
+    /// The `SetRatios` proposal the rebalance executes, marked executed once it commits
+    pub(crate) proposal_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RebalanceConfig {
+    pub oracle_id: Option<AccountId>,
+    pub target_weights: Vec<u32>,
+    pub deviation_threshold_bps: u32,
+    pub cooldown_sec: U64,
+    pub last_rebalance_ns: U64,
+    pub nonce: U64,
+    pub amm_id: Option<AccountId>,
+    pub max_slippage_bps: u32,
+    pub pending_target_ratios: Option<Vec<u32>>,
+    /// From when anyone can cancel the pending rebalance
+    pub pending_expires_ns: Option<U64>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct RebalanceEvent<'a> {
+    nonce: U64,
+    timestamp: U64,
+    old_ratios: &'a [TokenWithRatio],
+    new_ratios: &'a [TokenWithRatio],
+    prices: &'a [Price],
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct RebalancePendingEvent<'a> {
+    nonce: U64,
+    target_ratios: &'a [u32],
+    prices: &'a [Price],
+}
+
+impl PendingRebalance {
+    pub(crate) fn is_expired(&self) -> bool {
+        env::block_timestamp() >= self.created_ns + PENDING_REBALANCE_TTL_NS
+    }
+
+    /// The ratios the reserves back after the swaps done so far, rounded down
+    pub(crate) fn backed_ratios(&self, old_ratios: &[u32]) -> Vec<u32> {
+        old_ratios
+            .iter()
+            .enumerate()
+            .map(|(i, ratio)| {
+                let reserve = self.supply * *ratio as u128 - self.sold[i] + self.bought[i];
+                std::cmp::min(reserve / self.supply, u32::MAX as u128) as u32
+            })
+            .collect()
+    }
+}
+
+impl Rebalancer {
+    pub(crate) fn new() -> Self {
+        Self {
+            oracle_id: None,
+            target_weights: vec![],
+            deviation_threshold_bps: 0,
+            cooldown_ns: 0,
+            last_rebalance_ns: 0,
+            nonce: 0,
+            amm_id: None,
+            max_slippage_bps: 0,
+            pending: None,
+        }
+    }
+
+    /// Wrapping changes the reserves which a pending rebalance is swapping
+    pub(crate) fn assert_not_pending(&self) {
+        if self.pending.is_some() {
+            panic!("The set is being rebalanced");
+        }
+    }
+
+    fn assert_can_rebalance(&self) {
+        self.assert_not_pending();
+        if self.oracle_id.is_none() {
+            panic!("Rebalancing is not configured: no oracle set");
+        }
+        if self.deviation_threshold_bps == 0 {
+            panic!("Rebalancing is not configured: no deviation threshold set");
+        }
+        if self.target_weights.is_empty() {
+            panic!("Rebalancing is not configured: no target weights set");
+        }
+        if self.last_rebalance_ns > 0
+            && env::block_timestamp() < self.last_rebalance_ns + self.cooldown_ns
+        {
+            panic!(
+                "Rebalancing is on cooldown until {}",
+                self.last_rebalance_ns + self.cooldown_ns
+            );
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Set the oracle used for rebalancing, the minimum deviation (in basis points) from the
+    /// target weights required to rebalance and the minimum time between rebalances. The
+    /// threshold has to be positive.
+    #[payable]
+    pub fn set_rebalance_config(
+        &mut self,
+        oracle_id: ValidAccountId,
+        deviation_threshold_bps: u32,
+        cooldown_sec: U64,
+    ) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if deviation_threshold_bps == 0 || deviation_threshold_bps > WEIGHT_DENOMINATOR {
+            panic!(
+                "Expected the deviation threshold to be between 1 and {}",
+                WEIGHT_DENOMINATOR
+            );
+        }
+        if cooldown_sec.0 > MAX_COOLDOWN_SEC {
+            panic!("Expected the cooldown to be at most {} seconds", MAX_COOLDOWN_SEC);
+        }
+        self.rebalancer.oracle_id = Some(oracle_id.into());
+        self.rebalancer.deviation_threshold_bps = deviation_threshold_bps;
+        self.rebalancer.cooldown_ns = cooldown_sec.0 * 1_000_000_000;
+    }
+
+    /// Set the target weight of every component, in basis points and in the order of the set's
+    /// ratios
+    #[payable]
+    pub fn set_target_weights(&mut self, target_weights: Vec<u32>) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        self.internal_set_target_weights(target_weights);
+    }
+
+    /// Fetch the components' prices from the oracle and rebalance the set towards its target
+    /// weights once the cooldown has passed
+    #[payable]
+    pub fn rebalance(&mut self) -> Promise {
+        utils::assert_1_yocto();
+        self.assert_owner_or_role(Role::Rebalancer);
+        self.rebalancer.assert_can_rebalance();
+        self.fetch_prices().then(ext_self::on_rebalance_prices(
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_REBALANCE_PRICES,
+        ))
+    }
+
+    /// Compute the new ratios from the oracle's prices and start moving the set towards them, see
+    /// `start_rebalance`
+    #[private]
+    pub fn on_rebalance_prices(&mut self) -> Vec<TokenWithRatio> {
+        // Re-check, another rebalance may have landed while the prices were fetched
+        self.rebalancer.assert_can_rebalance();
+        let prices = self.received_prices();
+
+        let ratios: Vec<u32> = self.set_info.get_ratios().iter().map(|r| r.ratio).collect();
+        let deviation = max_weight_deviation_bps(&ratios, &prices, &self.rebalancer.target_weights);
+        if deviation < self.rebalancer.deviation_threshold_bps {
+            panic!(
+                "The maximum deviation of {} bps is below the rebalancing threshold of {} bps",
+                deviation, self.rebalancer.deviation_threshold_bps
+            );
+        }
+        let new_ratios =
+            compute_rebalanced_ratios(&ratios, &prices, &self.rebalancer.target_weights);
+        self.start_rebalance(&new_ratios, &prices, None);
+        self.set_info
+            .get_ratios()
+            .into_iter()
+            .zip(new_ratios.into_iter())
+            .map(|(token, ratio)| TokenWithRatio { ratio, ..token })
+            .collect()
+    }
+
+    pub fn get_rebalance_config(&self) -> RebalanceConfig {
+        RebalanceConfig {
+            oracle_id: self.rebalancer.oracle_id.clone(),
+            target_weights: self.rebalancer.target_weights.clone(),
+            deviation_threshold_bps: self.rebalancer.deviation_threshold_bps,
+            cooldown_sec: (self.rebalancer.cooldown_ns / 1_000_000_000).into(),
+            last_rebalance_ns: self.rebalancer.last_rebalance_ns.into(),
+            nonce: self.rebalancer.nonce.into(),
+            amm_id: self.rebalancer.amm_id.clone(),
+            max_slippage_bps: self.rebalancer.max_slippage_bps,
+            pending_target_ratios: self
+                .rebalancer
+                .pending
+                .as_ref()
+                .map(|pending| pending.target_ratios.clone()),
+            pending_expires_ns: self
+                .rebalancer
+                .pending
+                .as_ref()
+                .map(|pending| (pending.created_ns + PENDING_REBALANCE_TTL_NS).into()),
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_set_target_weights(&mut self, target_weights: Vec<u32>) {
+        if target_weights.len() as u64 != self.set_info.ratios.len() {
+            panic!(
+                "Expected a target weight for each of the {} tokens",
+                self.set_info.ratios.len()
+            );
+        }
+        if target_weights.iter().any(|w| *w == 0) {
+            panic!("Expected every target weight to be positive");
+        }
+        if target_weights.iter().sum::<u32>() != WEIGHT_DENOMINATOR {
+            panic!("Expected the target weights to sum up to {}", WEIGHT_DENOMINATOR);
+        }
+        self.rebalancer.target_weights = target_weights;
+    }
+
+    /// Ask the oracle for the prices of the components
+    pub(crate) fn fetch_prices(&self) -> Promise {
+        let oracle_id = self.rebalancer.oracle_id.as_ref().expect("No oracle set");
+        ext_price_oracle::get_price_data(
+            Some(self.set_info.token_ids()),
+            oracle_id,
+            NO_DEPOSIT,
+            GAS_FOR_GET_PRICE_DATA,
+        )
+    }
+
+    /// The prices `fetch_prices` returned, ordered like the set's ratios
+    pub(crate) fn received_prices(&self) -> Vec<Price> {
+        let price_data: PriceData = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice(&value).expect("Failed to parse the oracle's price data")
+            }
+            _ => panic!("Failed to fetch prices from the oracle"),
+        };
+        self.prices_by_component(price_data)
+    }
+
+    /// Move the set towards `new_ratios`, executing `proposal_id` if given. A set without supply
+    /// holds no reserves, so its ratios are committed right away. Otherwise the rebalance is left
+    /// pending until the reserves have been swapped through `execute_rebalance`.
+    pub(crate) fn start_rebalance(
+        &mut self,
+        new_ratios: &[u32],
+        prices: &[Price],
+        proposal_id: Option<u64>,
+    ) {
+        let supply = self.token.total_supply;
+        if supply == 0 {
+            self.commit_rebalance(new_ratios, prices, proposal_id);
+            return;
+        }
+        self.rebalancer.pending = Some(PendingRebalance {
+            target_ratios: new_ratios.to_vec(),
+            prices: prices.to_vec(),
+            supply,
+            sold: vec![0; new_ratios.len()],
+            bought: vec![0; new_ratios.len()],
+            in_flight: false,
+            created_ns: env::block_timestamp(),
+            proposal_id,
+        });
+        events::emit(
+            "rebalance_pending",
+            &RebalancePendingEvent {
+                nonce: (self.rebalancer.nonce + 1).into(),
+                target_ratios: new_ratios,
+                prices,
+            },
+        );
+    }
+
+    /// Replace the set's ratios and record the new composition as an event. `proposal_id` is the
+    /// proposal whose ratios these are, if any.
+    pub(crate) fn commit_rebalance(
+        &mut self,
+        new_ratios: &[u32],
+        prices: &[Price],
+        proposal_id: Option<u64>,
+    ) {
+        let old_ratios = self.set_info.get_ratios();
+        self.set_info.update_ratios(new_ratios);
+        self.rebalancer.pending = None;
+        self.rebalancer.last_rebalance_ns = env::block_timestamp();
+        self.rebalancer.nonce += 1;
+        events::emit(
+            "rebalance",
+            &RebalanceEvent {
+                nonce: self.rebalancer.nonce.into(),
+                timestamp: env::block_timestamp().into(),
+                old_ratios: &old_ratios,
+                new_ratios: &self.set_info.get_ratios(),
+                prices,
+            },
+        );
+        self.refresh_icon();
+        if let Some(proposal_id) = proposal_id {
+            self.mark_proposal_executed(proposal_id);
+        }
+    }
+
+    /// Order the oracle's prices like the set's ratios, checking that they are fresh
+    fn prices_by_component(&self, price_data: PriceData) -> Vec<Price> {
+        let recency_ns = price_data.recency_duration_sec as u64 * 1_000_000_000;
+        if price_data.timestamp.0 + recency_ns < env::block_timestamp() {
+            panic!("The oracle's prices are stale");
+        }
+        self.set_info
+            .token_ids()
+            .iter()
+            .map(|token_id| {
+                price_data
+                    .prices
+                    .iter()
+                    .find(|p| &p.asset_id == token_id)
+                    .and_then(|p| p.price.clone())
+                    .unwrap_or_else(|| panic!("The oracle has no price for {}", token_id))
+            })
+            .collect()
+    }
+}
+
+/// Bring all prices to the same number of decimals so that values can be compared
+pub(crate) fn normalize_prices(prices: &[Price]) -> Vec<U256> {
+    let max_decimals = prices.iter().map(|p| p.decimals).max().unwrap_or(0);
+    if max_decimals > MAX_PRICE_DECIMALS {
+        panic!("Expected prices with at most {} decimals", MAX_PRICE_DECIMALS);
+    }
+    prices
+        .iter()
+        .map(|p| {
+            if p.multiplier.0 == 0 {
+                panic!("Expected every price to be positive");
+            }
+            U256::from(p.multiplier.0) * U256::exp10((max_decimals - p.decimals) as usize)
+        })
+        .collect()
+}
+
+/// The value of `ratios` at `prices`, per component
+fn component_values(ratios: &[u32], prices: &[U256]) -> Vec<U256> {
+    ratios
+        .iter()
+        .zip(prices.iter())
+        .map(|(r, p)| p.checked_mul(U256::from(*r)).expect("The set's value overflows"))
+        .collect()
+}
+
+fn sum_values(values: &[U256]) -> U256 {
+    values.iter().fold(U256::zero(), |acc, v| {
+        acc.checked_add(*v).expect("The set's value overflows")
+    })
+}
+
+/// Check that `new_ratios` are worth no more than `ratios` at `prices`, as the swaps have to pay
+/// for them, and at most `max_slippage_bps` less
+pub(crate) fn assert_value_kept(
+    ratios: &[u32],
+    new_ratios: &[u32],
+    prices: &[Price],
+    max_slippage_bps: u32,
+) {
+    let prices = normalize_prices(prices);
+    let value = sum_values(&component_values(ratios, &prices));
+    let new_value = sum_values(&component_values(new_ratios, &prices));
+    if new_value > value {
+        panic!("The new ratios are worth more than the current ones");
+    }
+    let min_value = value * U256::from(WEIGHT_DENOMINATOR - max_slippage_bps)
+        / U256::from(WEIGHT_DENOMINATOR);
+    if new_value < min_value {
+        panic!(
+            "The new ratios are worth more than {} bps less than the current ones",
+            max_slippage_bps
+        );
+    }
+}
+
+/// The largest distance, in basis points, between a component's share of the set's value and
+/// its target weight
+pub(crate) fn max_weight_deviation_bps(
+    ratios: &[u32],
+    prices: &[Price],
+    target_weights: &[u32],
+) -> u32 {
+    let values = component_values(ratios, &normalize_prices(prices));
+    let nav = sum_values(&values);
+    values
+        .iter()
+        .zip(target_weights.iter())
+        .map(|(v, w)| {
+            let weight = v
+                .checked_mul(U256::from(WEIGHT_DENOMINATOR))
+                .expect("The set's value overflows")
+                / nav;
+            let weight = weight.as_u32();
+            if weight > *w {
+                weight - w
+            } else {
+                w - weight
+            }
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// The new ratios which keep the value of one set token the same while matching the target
+/// weights. Ratios are rounded down.
+pub(crate) fn compute_rebalanced_ratios(
+    ratios: &[u32],
+    prices: &[Price],
+    target_weights: &[u32],
+) -> Vec<u32> {
+    let prices = normalize_prices(prices);
+    let nav = sum_values(&component_values(ratios, &prices));
+    prices
+        .iter()
+        .zip(target_weights.iter())
+        .map(|(p, w)| {
+            let value = nav.checked_mul(U256::from(*w)).expect("The set's value overflows");
+            let ratio = value / (U256::from(WEIGHT_DENOMINATOR) * *p);
+            if ratio.is_zero() {
+                panic!("A target weight of {} bps is too small to be represented as a ratio", w);
+            }
+            if ratio > U256::from(u32::MAX) {
+                panic!("A target weight of {} bps overflows the ratio", w);
+            }
+            ratio.as_u32()
+        })
+        .collect()
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    fn price(multiplier: u128, decimals: u8) -> Price {
+        Price { multiplier: multiplier.into(), decimals }
+    }
+
+    #[test]
+    fn test_rebalance_preserves_nav() {
+        // Token 0 is worth twice token 1, so a 1:1 set is weighted 2/3 to 1/3
+        let ratios = vec![1_000, 1_000];
+        let prices = vec![price(2, 0), price(1, 0)];
+        let weights = vec![5_000, 5_000];
+        assert_eq!(max_weight_deviation_bps(&ratios, &prices, &weights), 1_667);
+
+        let new_ratios = compute_rebalanced_ratios(&ratios, &prices, &weights);
+        assert_eq!(new_ratios, vec![750, 1_500]);
+        assert_eq!(max_weight_deviation_bps(&new_ratios, &prices, &weights), 0);
+    }
+
+    #[test]
+    fn test_rebalance_normalizes_decimals() {
+        let ratios = vec![100, 100];
+        let prices = vec![price(2_000, 3), price(1, 0)];
+        let weights = vec![2_000, 8_000];
+        assert_eq!(compute_rebalanced_ratios(&ratios, &prices, &weights), vec![30, 240]);
+    }
+
+    #[test]
+    fn test_value_kept_within_slippage() {
+        let prices = vec![price(2, 0), price(1, 0)];
+        // Worth 2 * 750 + 1_480 = 2_980 instead of 3_000, 67 bps less
+        assert_value_kept(&[1_000, 1_000], &[750, 1_480], &prices, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "The new ratios are worth more than the current ones")]
+    fn test_value_kept_refuses_raised_ratios() {
+        let prices = vec![price(2, 0), price(1, 0)];
+        assert_value_kept(&[1_000, 1_000], &[1_000, 1_001], &prices, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "The new ratios are worth more than 100 bps less")]
+    fn test_value_kept_refuses_dropped_value() {
+        let prices = vec![price(2, 0), price(1, 0)];
+        assert_value_kept(&[1_000, 1_000], &[500, 1_000], &prices, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected prices with at most 24 decimals")]
+    fn test_rebalance_bounds_decimals() {
+        let prices = vec![price(1, 0), price(1, 255)];
+        compute_rebalanced_ratios(&[100, 100], &prices, &[5_000, 5_000]);
+    }
+}
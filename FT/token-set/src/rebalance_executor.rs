@@ -0,0 +1,361 @@
+//! Swaps a pending rebalance's reserves through a Ref-Finance-style AMM.
+//!
+//! Every swap leg sells a component held above its target ratio for one held below it by calling
+//! `ft_transfer_call` on the sold token with the AMM as receiver. The legs' `min_amount_out` may
+//! not be further below the oracle price than the configured slippage, so anyone can execute them.
+//! The reserves are credited with what the AMM actually paid out, measured as the change of the
+//! set's balance of the bought tokens around the swaps. The AMM therefore has to pay out before
+//! its `ft_transfer_call` resolves, and deposits and withdrawals are refused while swaps are in
+//! flight. The rebalance stays pending, and can be executed in several calls, until the excess of
+//! every component has been sold and the deficit of every component bought. Only then are the
+//! target ratios committed.
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::{self, json};
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult,
+};
+
+use crate::external::ext_fungible_token;
+use crate::rebalance::{normalize_prices, WEIGHT_DENOMINATOR};
+use crate::utils::{self, U256};
+use crate::Contract;
+
+const NO_DEPOSIT: Balance = 0;
+const ONE_YOCTO: Balance = 1;
+const GAS_FOR_SWAP_LEG: Gas = 60_000_000_000_000;
+const GAS_FOR_FT_BALANCE_OF: Gas = 5_000_000_000_000;
+const GAS_FOR_CALLBACK: Gas = 10_000_000_000_000;
+const GAS_FOR_ON_REBALANCE_BOUGHT: Gas = 20_000_000_000_000;
+
+/// One swap of a rebalance, as passed to the AMM's swap action
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapLeg {
+    pub pool_id: u64,
+    pub token_in: ValidAccountId,
+    pub token_out: ValidAccountId,
+    pub amount_in: U128,
+    pub min_amount_out: U128,
+}
+
+#[ext_contract(ext_self)]
+pub trait RebalanceExecutorCallbacks {
+    fn on_rebalance_started(
+        &mut self,
+        legs: Vec<SwapLeg>,
+        bought_ids: Vec<AccountId>,
+    ) -> PromiseOrValue<bool>;
+
+    fn on_rebalance_legs(
+        &mut self,
+        legs: Vec<SwapLeg>,
+        bought_ids: Vec<AccountId>,
+        balances_before: Vec<U128>,
+    ) -> Promise;
+
+    fn on_rebalance_bought(
+        &mut self,
+        bought_ids: Vec<AccountId>,
+        balances_before: Vec<U128>,
+    ) -> bool;
+}
+
+/// The set's balance of each of `token_ids`
+fn query_balances(token_ids: &[AccountId]) -> Promise {
+    utils::join_promises(token_ids.iter().map(|token_id| {
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            token_id,
+            NO_DEPOSIT,
+            GAS_FOR_FT_BALANCE_OF,
+        )
+    }))
+}
+
+/// The balances returned by the promises `query_balances` joined, `None` if any query failed
+fn balance_results(count: usize) -> Option<Vec<Balance>> {
+    (0..count)
+        .map(|k| match env::promise_result(k as u64) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<U128>(&value).ok().map(|balance| balance.0)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Gas for `on_rebalance_legs` and the calls it makes
+fn gas_for_on_rebalance_legs(bought_count: usize) -> Gas {
+    GAS_FOR_CALLBACK + bought_count as Gas * GAS_FOR_FT_BALANCE_OF + GAS_FOR_ON_REBALANCE_BOUGHT
+}
+
+impl Contract {
+    /// Deposits and withdrawals would distort the balance changes swaps are measured with
+    pub(crate) fn assert_not_swapping(&self) {
+        if self.rebalancer.pending.as_ref().map_or(false, |pending| pending.in_flight) {
+            panic!("The set's reserves are being swapped");
+        }
+    }
+
+    fn abort_rebalance_execution(&mut self, reason: &str) -> bool {
+        log!("{}", reason);
+        if let Some(pending) = self.rebalancer.pending.as_mut() {
+            pending.in_flight = false;
+        }
+        false
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Set the AMM which the reserves are swapped through and how far below the oracle price
+    /// (in basis points) a swap's minimum output may be
+    #[payable]
+    pub fn set_rebalance_executor(&mut self, amm_id: ValidAccountId, max_slippage_bps: u32) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if max_slippage_bps > WEIGHT_DENOMINATOR {
+            panic!("Expected the maximum slippage to be at most {}", WEIGHT_DENOMINATOR);
+        }
+        self.rebalancer.amm_id = Some(amm_id.into());
+        self.rebalancer.max_slippage_bps = max_slippage_bps;
+    }
+
+    /// Abandon the pending rebalance. The owner can do so before any of its swaps went through,
+    /// anyone once it expired. The ratios of an expired rebalance which swapped some reserves
+    /// are set to what the reserves back.
+    #[payable]
+    pub fn cancel_rebalance(&mut self) {
+        utils::assert_1_yocto();
+        let pending = self.rebalancer.pending.as_ref().expect("No rebalance is pending");
+        if pending.in_flight {
+            panic!("Cannot cancel a rebalance whose swaps are being executed");
+        }
+        let swapped = pending.sold.iter().any(|sold| *sold > 0);
+        if !pending.is_expired() {
+            self.assert_owner();
+            if swapped {
+                panic!("Cannot cancel a rebalance which already swapped reserves");
+            }
+        }
+        if !swapped {
+            self.rebalancer.pending = None;
+            return;
+        }
+        let old_ratios: Vec<u32> = self.set_info.get_ratios().iter().map(|r| r.ratio).collect();
+        let new_ratios = pending.backed_ratios(&old_ratios);
+        let prices = pending.prices.clone();
+        // Only part of the proposal's ratios are reached, it stays passed
+        self.commit_rebalance(&new_ratios, &prices, None);
+    }
+
+    /// Swap the pending rebalance's reserves through the AMM
+    pub fn execute_rebalance(&mut self, legs: Vec<SwapLeg>) -> Promise {
+        if legs.is_empty() {
+            panic!("Expected at least one swap leg");
+        }
+        if self.rebalancer.amm_id.is_none() {
+            panic!("No AMM set for rebalancing");
+        }
+        let max_slippage_bps = self.rebalancer.max_slippage_bps;
+        let token_ids = self.set_info.token_ids();
+        let old_ratios: Vec<u32> = self.set_info.get_ratios().iter().map(|r| r.ratio).collect();
+        let pending = self.rebalancer.pending.as_mut().expect("No rebalance is pending");
+        if pending.in_flight {
+            panic!("The rebalance is already being executed");
+        }
+        if pending.is_expired() {
+            panic!("The rebalance expired, it can only be cancelled");
+        }
+
+        let prices = normalize_prices(&pending.prices);
+        let index_of = |token_id: &ValidAccountId| {
+            token_ids
+                .iter()
+                .position(|id| id == token_id.as_ref())
+                .unwrap_or_else(|| panic!("{} is not in the set", token_id))
+        };
+        let mut selling: Vec<Balance> = vec![0; token_ids.len()];
+        for leg in legs.iter() {
+            let (i, j) = (index_of(&leg.token_in), index_of(&leg.token_out));
+            if pending.target_ratios[i] >= old_ratios[i] {
+                panic!("{} is not held above its target ratio", leg.token_in);
+            }
+            if pending.target_ratios[j] <= old_ratios[j] {
+                panic!("{} is not held below its target ratio", leg.token_out);
+            }
+            selling[i] += leg.amount_in.0;
+
+            let expected_out = U256::from(leg.amount_in.0) * prices[i] / prices[j];
+            let min_out = (expected_out * U256::from(WEIGHT_DENOMINATOR - max_slippage_bps)
+                / U256::from(WEIGHT_DENOMINATOR))
+            .as_u128();
+            if leg.min_amount_out.0 < min_out {
+                panic!(
+                    "Expected a min_amount_out of at least {} for selling {} {}",
+                    min_out, leg.amount_in.0, leg.token_in
+                );
+            }
+        }
+        for i in 0..token_ids.len() {
+            let excess =
+                pending.supply * old_ratios[i].saturating_sub(pending.target_ratios[i]) as u128;
+            if pending.sold[i] + selling[i] > excess {
+                panic!(
+                    "Cannot sell more than the excess of {} {}, {} already sold",
+                    excess, token_ids[i], pending.sold[i]
+                );
+            }
+        }
+        pending.in_flight = true;
+
+        let mut bought_ids: Vec<AccountId> = vec![];
+        for leg in legs.iter() {
+            if !bought_ids.iter().any(|id| id == leg.token_out.as_ref()) {
+                bought_ids.push(leg.token_out.clone().into());
+            }
+        }
+        let gas = GAS_FOR_CALLBACK
+            + legs.len() as Gas * GAS_FOR_SWAP_LEG
+            + gas_for_on_rebalance_legs(bought_ids.len());
+        query_balances(&bought_ids).then(ext_self::on_rebalance_started(
+            legs,
+            bought_ids,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas,
+        ))
+    }
+
+    /// Swap the legs now that the balances of the bought tokens are known
+    #[private]
+    pub fn on_rebalance_started(
+        &mut self,
+        legs: Vec<SwapLeg>,
+        bought_ids: Vec<AccountId>,
+    ) -> PromiseOrValue<bool> {
+        let balances_before = match balance_results(bought_ids.len()) {
+            Some(balances) => balances,
+            None => {
+                let reason = "Could not read the reserves before swapping";
+                return PromiseOrValue::Value(self.abort_rebalance_execution(reason));
+            }
+        };
+        let amm_id = self.rebalancer.amm_id.clone().expect("No AMM set for rebalancing");
+        let swaps = utils::join_promises(legs.iter().map(|leg| {
+            let msg = json!({
+                "actions": [{
+                    "pool_id": leg.pool_id,
+                    "token_in": leg.token_in,
+                    "token_out": leg.token_out,
+                    "amount_in": leg.amount_in,
+                    "min_amount_out": leg.min_amount_out,
+                }]
+            });
+            ext_fungible_token::ft_transfer_call(
+                amm_id.clone(),
+                leg.amount_in,
+                None,
+                msg.to_string(),
+                leg.token_in.as_ref(),
+                ONE_YOCTO,
+                GAS_FOR_SWAP_LEG,
+            )
+        }));
+        let balances_before: Vec<U128> = balances_before.into_iter().map(U128).collect();
+        let gas = gas_for_on_rebalance_legs(bought_ids.len());
+        swaps
+            .then(ext_self::on_rebalance_legs(
+                legs,
+                bought_ids,
+                balances_before,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas,
+            ))
+            .into()
+    }
+
+    /// Record what the legs sold and read the balances of the bought tokens again
+    #[private]
+    pub fn on_rebalance_legs(
+        &mut self,
+        legs: Vec<SwapLeg>,
+        bought_ids: Vec<AccountId>,
+        balances_before: Vec<U128>,
+    ) -> Promise {
+        let token_ids = self.set_info.token_ids();
+        let pending = self.rebalancer.pending.as_mut().expect("No rebalance is pending");
+        for (k, leg) in legs.iter().enumerate() {
+            let i = token_ids.iter().position(|id| id == leg.token_in.as_ref()).unwrap();
+            // `ft_transfer_call` resolves to the amount the AMM kept
+            let used: Balance = match env::promise_result(k as u64) {
+                PromiseResult::Successful(value) => {
+                    serde_json::from_slice::<U128>(&value).map(|used| used.0).unwrap_or(0)
+                }
+                _ => 0,
+            };
+            if used < leg.amount_in.0 {
+                log!("Swapping {} {} for {} failed", leg.amount_in.0, leg.token_in, leg.token_out);
+            }
+            pending.sold[i] += used;
+            self.set_info.remove_reserve(&token_ids[i], used);
+        }
+        query_balances(&bought_ids).then(ext_self::on_rebalance_bought(
+            bought_ids,
+            balances_before,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_REBALANCE_BOUGHT,
+        ))
+    }
+
+    /// Credit the reserves with what the swaps paid out and commit the target ratios once every
+    /// component reached them
+    ///
+    /// return whether the rebalance is complete
+    #[private]
+    pub fn on_rebalance_bought(
+        &mut self,
+        bought_ids: Vec<AccountId>,
+        balances_before: Vec<U128>,
+    ) -> bool {
+        let balances_after = match balance_results(bought_ids.len()) {
+            Some(balances) => balances,
+            None => {
+                // What the swaps paid out stays outside of the reserves, as untracked surplus
+                let reason = "Could not read the reserves after swapping";
+                return self.abort_rebalance_execution(reason);
+            }
+        };
+        let token_ids = self.set_info.token_ids();
+        let old_ratios: Vec<u32> = self.set_info.get_ratios().iter().map(|r| r.ratio).collect();
+        let pending = self.rebalancer.pending.as_mut().expect("No rebalance is pending");
+        pending.in_flight = false;
+        for (k, token_id) in bought_ids.iter().enumerate() {
+            let j = token_ids.iter().position(|id| id == token_id).unwrap();
+            let bought = balances_after[k].saturating_sub(balances_before[k].0);
+            pending.bought[j] += bought;
+            self.set_info.add_reserve(token_id, bought);
+        }
+
+        let complete = (0..token_ids.len()).all(|i| {
+            let (old, target) = (old_ratios[i] as u128, pending.target_ratios[i] as u128);
+            if target <= old {
+                pending.sold[i] == pending.supply * (old - target)
+            } else {
+                pending.bought[i] >= pending.supply * (target - old)
+            }
+        });
+        if !complete {
+            return false;
+        }
+        let new_ratios = pending.target_ratios.clone();
+        let prices = pending.prices.clone();
+        let proposal_id = pending.proposal_id;
+        self.commit_rebalance(&new_ratios, &prices, proposal_id);
+        true
+    }
+}
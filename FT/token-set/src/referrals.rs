@@ -0,0 +1,157 @@
+//! Referral rewards, giving integrators a share of the owner fee on the wraps they bring.
+//!
+//! A wrap naming a referrer redirects `rate_bps` of the owner fee to the referrer. Like escrowed
+//! set tokens, the referral rewards are backed at once but only minted when the referrer claims
+//! them. The owner picks the rate, up to a cap which only governance can change.
+//!
+//! The referrer is named on `wrap`, the wrap action of a batch, or in the `msg` of a deposit
+//! through `ft_transfer_call` which wraps the deposited tokens (see `DepositMsg`).
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::rebalance::WEIGHT_DENOMINATOR;
+use crate::utils;
+use crate::{events, Contract};
+
+const DEFAULT_MAX_RATE_BPS: u32 = 5_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralConfig {
+    /// The share of the owner fee going to the referrer, in basis points
+    pub rate_bps: u32,
+    /// The highest rate the owner can set, in basis points
+    pub max_rate_bps: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralEarnings {
+    /// The set tokens the referrer can claim
+    pub claimable: U128,
+    /// All the set tokens the referrer earned, claimed or not
+    pub total_earned: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct ReferralEvent<'a> {
+    referrer_id: &'a AccountId,
+    account_id: &'a AccountId,
+    amount: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Referrals {
+    pub(crate) rate_bps: u32,
+    pub(crate) max_rate_bps: u32,
+    claimable: LookupMap<AccountId, Balance>,
+    earned: LookupMap<AccountId, Balance>,
+    /// The sum of the claimable rewards
+    pub(crate) total_claimable: Balance,
+}
+
+impl Referrals {
+    pub(crate) fn new() -> Self {
+        Self {
+            rate_bps: 0,
+            max_rate_bps: DEFAULT_MAX_RATE_BPS,
+            claimable: LookupMap::new(b"refc".to_vec()),
+            earned: LookupMap::new(b"refe".to_vec()),
+            total_claimable: 0,
+        }
+    }
+
+    /// Change the cap, lowering the rate along with it
+    pub(crate) fn set_max_rate(&mut self, max_rate_bps: u32) {
+        if max_rate_bps > WEIGHT_DENOMINATOR {
+            panic!("Expected the referral rate cap to be at most {}", WEIGHT_DENOMINATOR);
+        }
+        self.max_rate_bps = max_rate_bps;
+        self.rate_bps = std::cmp::min(self.rate_bps, max_rate_bps);
+    }
+}
+
+impl Contract {
+    /// The rate a wrap by `caller` referred by `referrer_id` earns the referrer
+    pub(crate) fn referral_rate(&self, caller: &AccountId, referrer_id: Option<&AccountId>) -> u32 {
+        match referrer_id {
+            Some(referrer_id) => {
+                if referrer_id == caller || referrer_id == &self.owner_id {
+                    panic!("{} cannot be the referrer of the wrap", referrer_id);
+                }
+                self.referrals.rate_bps
+            }
+            None => 0,
+        }
+    }
+
+    /// Credit `referrer_id` the referral fee of a wrap by `account_id`
+    pub(crate) fn credit_referral(
+        &mut self,
+        referrer_id: &AccountId,
+        account_id: &AccountId,
+        amount: Balance,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let claimable = self.referrals.claimable.get(referrer_id).unwrap_or(0);
+        self.referrals.claimable.insert(referrer_id, &(claimable + amount));
+        let earned = self.referrals.earned.get(referrer_id).unwrap_or(0);
+        self.referrals.earned.insert(referrer_id, &(earned + amount));
+        self.referrals.total_claimable += amount;
+        let event = ReferralEvent { referrer_id, account_id, amount: amount.into() };
+        events::emit("referral", &event);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Set the share of the owner fee going to referrers, up to the cap
+    #[payable]
+    pub fn set_referral_rate(&mut self, rate_bps: u32) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        if rate_bps > self.referrals.max_rate_bps {
+            panic!("Expected the referral rate to be at most {}", self.referrals.max_rate_bps);
+        }
+        self.referrals.rate_bps = rate_bps;
+    }
+
+    pub fn get_referral_config(&self) -> ReferralConfig {
+        ReferralConfig {
+            rate_bps: self.referrals.rate_bps,
+            max_rate_bps: self.referrals.max_rate_bps,
+        }
+    }
+
+    pub fn get_referral_earnings(&self, account_id: ValidAccountId) -> ReferralEarnings {
+        ReferralEarnings {
+            claimable: self.referrals.claimable.get(account_id.as_ref()).unwrap_or(0).into(),
+            total_earned: self.referrals.earned.get(account_id.as_ref()).unwrap_or(0).into(),
+        }
+    }
+
+    /// Mint the caller's referral rewards to them. An unregistered caller is registered, paying
+    /// the storage from the attached deposit.
+    ///
+    /// return the amount claimed
+    #[payable]
+    pub fn claim_referral_rewards(&mut self) -> U128 {
+        utils::assert_at_least_1_yocto();
+        let caller = env::predecessor_account_id();
+        let amount = self.referrals.claimable.remove(&caller).unwrap_or(0);
+        if amount == 0 {
+            panic!("No referral rewards to claim");
+        }
+        let storage_cost = self.register_with_deposit(&caller, env::attached_deposit());
+        self.referrals.total_claimable -= amount;
+        self.checkpointed(&[caller.clone()], |this| this.token.internal_deposit(&caller, amount));
+        utils::refund_deposit(env::attached_deposit() - storage_cost);
+        amount.into()
+    }
+}
@@ -0,0 +1,125 @@
+//! The reserves backing the set tokens and a check that the set holds them.
+//!
+//! The reserve of a component is what the set holds of it for its set tokens: it grows when
+//! tokens are wrapped, rewards reinvested or a rebalance buys the component, and shrinks when set
+//! tokens are burned or a rebalance sells the component. It covers at least the ratio of every
+//! set token, escrowed ones included, rounding and swaps leaving some more.
+//!
+//! Internal balances come on top of the reserves, their sum is kept per token (see
+//! `internal_balances`). `verify_backing` checks that the set holds its reserves, the reserves
+//! of its hosted sets, the rewards it keeps and the internal balances, and that the reserves
+//! cover the set tokens with the backing donated to them.
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, Promise, PromiseResult,
+};
+
+use crate::external::ext_fungible_token;
+use crate::utils;
+use crate::{Contract, TokenAmount};
+
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_FT_BALANCE_OF: Gas = 5_000_000_000_000;
+const GAS_FOR_ON_VERIFY_BACKING: Gas = 20_000_000_000_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ComponentBacking {
+    pub token_id: AccountId,
+    /// The set's balance of the component, `None` if it could not be read
+    pub balance: Option<U128>,
+    pub reserve: U128,
+    /// The sum of the internal balances of the component
+    pub internal: U128,
+    /// What the set tokens need, donated backing included
+    pub required: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BackingReport {
+    pub backed: bool,
+    pub components: Vec<ComponentBacking>,
+}
+
+#[ext_contract(ext_self)]
+pub trait ReservesCallbacks {
+    fn on_verify_backing(&self) -> BackingReport;
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_reserves(&self) -> Vec<TokenAmount> {
+        self.set_info
+            .token_ids()
+            .into_iter()
+            .map(|token_id| {
+                let amount = self.set_info.reserve_of(&token_id).into();
+                TokenAmount { token_id, amount }
+            })
+            .collect()
+    }
+
+    /// Read the set's balance of every component and check it against the reserves and the
+    /// internal balances
+    pub fn verify_backing(&self) -> Promise {
+        utils::join_promises(self.set_info.token_ids().iter().map(|token_id| {
+            ext_fungible_token::ft_balance_of(
+                env::current_account_id(),
+                token_id,
+                NO_DEPOSIT,
+                GAS_FOR_FT_BALANCE_OF,
+            )
+        }))
+        .then(ext_self::on_verify_backing(
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_VERIFY_BACKING,
+        ))
+    }
+
+    #[private]
+    pub fn on_verify_backing(&self) -> BackingReport {
+        let backed_supply = self.token.total_supply + self.unclaimed_supply();
+        let mut backed = true;
+        let components: Vec<ComponentBacking> = self
+            .set_info
+            .get_ratios()
+            .into_iter()
+            .enumerate()
+            .map(|(i, token)| {
+                let balance = match env::promise_result(i as u64) {
+                    PromiseResult::Successful(value) => {
+                        serde_json::from_slice::<U128>(&value).ok().map(|balance| balance.0)
+                    }
+                    _ => None,
+                };
+                let reserve = self.set_info.reserve_of(&token.token_id);
+                let internal = self.balances.total_of(&token.token_id);
+                let required = self.set_info.backing_of(&token, backed_supply, false);
+                let held = self.accounted_balance(&token.token_id);
+                if balance.map(|balance| balance < held).unwrap_or(true) || reserve < required {
+                    log!(
+                        "{} is not backed: holding {:?}, accounted {}, reserve {}, required {}",
+                        token.token_id,
+                        balance,
+                        held,
+                        reserve,
+                        required
+                    );
+                    backed = false;
+                }
+                ComponentBacking {
+                    token_id: token.token_id,
+                    balance: balance.map(U128),
+                    reserve: reserve.into(),
+                    internal: internal.into(),
+                    required: required.into(),
+                }
+            })
+            .collect();
+        BackingReport { backed, components }
+    }
+}
@@ -0,0 +1,54 @@
+//! Roles the owner can grant to other accounts for parts of the set's administration.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::ValidAccountId;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::utils;
+use crate::Contract;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    /// May update the token metadata
+    Metadata,
+    /// May maintain the allowlist and choose what it restricts
+    Allowlist,
+    /// May start rebalances towards the target weights
+    Rebalancer,
+}
+
+impl Contract {
+    pub(crate) fn has_role_internal(&self, role: Role, account_id: &AccountId) -> bool {
+        self.roles.contains(&(role, account_id.clone()))
+    }
+
+    /// The owner implicitly holds every role
+    pub(crate) fn assert_owner_or_role(&self, role: Role) {
+        let caller = env::predecessor_account_id();
+        if caller != self.owner_id && !self.has_role_internal(role, &caller) {
+            panic!("Only the owner or an account with the {:?} role can call this method", role);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn grant_role(&mut self, role: Role, account_id: ValidAccountId) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        self.roles.insert(&(role, account_id.into()));
+    }
+
+    #[payable]
+    pub fn revoke_role(&mut self, role: Role, account_id: ValidAccountId) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        self.roles.remove(&(role, account_id.into()));
+    }
+
+    pub fn has_role(&self, role: Role, account_id: ValidAccountId) -> bool {
+        self.has_role_internal(role, account_id.as_ref())
+    }
+}
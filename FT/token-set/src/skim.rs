@@ -0,0 +1,156 @@
+//! Recovering tokens sent to the set with a plain `ft_transfer` instead of `ft_transfer_call`.
+//!
+//! Such transfers credit nobody, so the set holds more than its reserves and the harvested
+//! rewards it keeps for holders. Skimming reads the set's balance of a token and moves part of
+//! that surplus to the owner's treasury, to a claimant's internal balance, or, for components,
+//! into the backing of the set tokens. Tokens which are not components have no reserve and can be
+//! recovered the same way.
+//!
+//! The surplus is what the set holds above its reserves, the reserves of its hosted sets, the
+//! rewards it keeps and the internal balances of depositors. Skimming is left to the owner, who
+//! names the amount to skim.
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Gas, Promise, PromiseResult};
+
+use crate::external::ext_fungible_token;
+use crate::utils;
+use crate::{events, Contract};
+
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_FT_BALANCE_OF: Gas = 5_000_000_000_000;
+const GAS_FOR_ON_SKIM: Gas = 20_000_000_000_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SkimDestination {
+    /// The internal balance of the owner
+    Treasury,
+    /// The internal balance of the given account, e.g. the sender of the stray tokens, which has
+    /// to be registered
+    Claimant { account_id: ValidAccountId },
+    /// The reserve of the component, raising its ratio like reinvested rewards
+    Backing,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct SkimEvent<'a> {
+    token_id: &'a AccountId,
+    amount: U128,
+    destination: &'a SkimDestination,
+}
+
+#[ext_contract(ext_self)]
+pub trait SkimCallbacks {
+    fn on_skim(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        destination: SkimDestination,
+    ) -> U128;
+}
+
+impl Contract {
+    /// What the set holds of `token_id` for its set tokens, hosted sets, holders and depositors
+    pub(crate) fn accounted_balance(&self, token_id: &AccountId) -> Balance {
+        self.set_info.reserve_of(token_id)
+            + self.multi_token.reserve_of(token_id)
+            + self.harvester.held_rewards(token_id)
+            + self.balances.total_of(token_id)
+    }
+
+    fn assert_can_skim(&self) {
+        if self.harvester.harvesting {
+            panic!("A harvest is in progress");
+        }
+        self.rebalancer.assert_not_pending();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Move `amount` of the set's surplus of `token_id` to `destination`. The amount cannot exceed
+    /// the balance of the set less what it accounts for, see `get_accounted_balance`.
+    ///
+    /// return the amount skimmed
+    #[payable]
+    pub fn skim(
+        &mut self,
+        token_id: ValidAccountId,
+        amount: U128,
+        destination: SkimDestination,
+    ) -> Promise {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        self.assert_can_skim();
+        if amount.0 == 0 {
+            panic!("Nothing to skim");
+        }
+        match &destination {
+            SkimDestination::Backing => {
+                if !self.set_info.token_ids().contains(token_id.as_ref()) {
+                    panic!("Only components can be skimmed into the backing");
+                }
+            }
+            SkimDestination::Claimant { account_id } => self.assert_registered(account_id.as_ref()),
+            SkimDestination::Treasury => {}
+        }
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            token_id.as_ref(),
+            NO_DEPOSIT,
+            GAS_FOR_FT_BALANCE_OF,
+        )
+        .then(ext_self::on_skim(
+            token_id.into(),
+            amount,
+            destination,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_SKIM,
+        ))
+    }
+
+    #[private]
+    pub fn on_skim(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        destination: SkimDestination,
+    ) -> U128 {
+        // A harvest or a rebalance started meanwhile could move the balance
+        self.assert_can_skim();
+        let balance = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .unwrap_or_else(|_| panic!("Could not read the balance of {}", token_id))
+                .0,
+            _ => panic!("Could not read the balance of {}", token_id),
+        };
+        let surplus = balance.saturating_sub(self.accounted_balance(&token_id));
+        if amount.0 > surplus {
+            panic!("Cannot skim {} of {}, the surplus is {}", amount.0, token_id, surplus);
+        }
+        match &destination {
+            SkimDestination::Treasury => {
+                self.balances.increase_balance(&self.owner_id, &token_id, amount.0)
+            }
+            SkimDestination::Claimant { account_id } => {
+                // The claimant may have unregistered while the balance was read
+                self.assert_registered(account_id.as_ref());
+                self.balances.increase_balance(account_id.as_ref(), &token_id, amount.0)
+            }
+            SkimDestination::Backing => self.reinvest(&token_id, amount.0),
+        }
+        let event = SkimEvent { token_id: &token_id, amount, destination: &destination };
+        events::emit("skim", &event);
+        amount
+    }
+
+    /// The set's balance of `token_id` which is accounted for by its reserves, the rewards it
+    /// holds and the internal balances. Whatever the set holds above this is skimmable.
+    pub fn get_accounted_balance(&self, token_id: ValidAccountId) -> U128 {
+        self.accounted_balance(token_id.as_ref()).into()
+    }
+}
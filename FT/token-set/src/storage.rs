@@ -0,0 +1,188 @@
+//! Storage management for the set token and the internal balances of its components.
+//!
+//! Besides its set token balance, an account holds an internal balance record for each
+//! component it deposited. Registration therefore charges for the fungible token account plus one
+//! internal balance record per component, and the contract only credits internal balances to
+//! registered accounts. Registration also pays for the record of the set tokens the account mints,
+//! see `supply_caps`. The deposit each account paid is recorded, and refunded when it unregisters
+//! even if the number of components changed since, less the storage of the mint record which is
+//! kept.
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, Promise, StorageUsage};
+
+use crate::utils;
+use crate::Contract;
+
+/// Upper bound of the storage taken by one internal balance record: the account id and the token
+/// id (64 bytes each at most), the balance, the collection's key prefix and the record overhead
+pub(crate) const INTERNAL_BALANCE_STORAGE_USAGE: StorageUsage = 64 + 64 + 16 + 8 + 40;
+/// Upper bound of the storage taken by the record of the deposit an account paid: the account id,
+/// the amount, the collection's key prefix and the record overhead
+const STORAGE_PAID_USAGE: StorageUsage = 64 + 16 + 3 + 40;
+/// Upper bound of the storage taken by the record of the set tokens an account minted: the
+/// account id, the amount, the collection's key prefix and the record overhead
+pub(crate) const MINTED_STORAGE_USAGE: StorageUsage = 64 + 16 + 3 + 40;
+
+impl Contract {
+    pub(crate) fn is_registered(&self, account_id: &AccountId) -> bool {
+        self.token.accounts.contains_key(account_id)
+    }
+
+    /// Internal balances take storage, so they are only kept for accounts which paid for it
+    pub(crate) fn assert_registered(&self, account_id: &AccountId) {
+        if !self.is_registered(account_id) {
+            panic!("The account {} is not registered", account_id);
+        }
+    }
+
+    /// Register `account_id` if it is not yet, paying the storage from `deposit`.
+    ///
+    /// return the amount of the deposit used
+    pub(crate) fn register_with_deposit(
+        &mut self,
+        account_id: &AccountId,
+        deposit: Balance,
+    ) -> Balance {
+        if self.is_registered(account_id) {
+            return 0;
+        }
+        let min_balance = self.storage_min_balance();
+        if deposit < min_balance {
+            panic!(
+                "{} is not registered: {} yoctoNEAR have to be attached to register it, got {}",
+                account_id, min_balance, deposit
+            );
+        }
+        self.token.internal_register_account(account_id);
+        self.storage_paid.insert(account_id, &min_balance);
+        min_balance
+    }
+
+    fn storage_min_balance(&self) -> Balance {
+        let usage = self.token.account_storage_usage
+            + STORAGE_PAID_USAGE
+            + MINTED_STORAGE_USAGE
+            + self.set_info.ratios.len() * INTERNAL_BALANCE_STORAGE_USAGE;
+        usage as Balance * env::storage_byte_cost()
+    }
+
+    /// The deposit `account_id` paid at registration. Accounts registered by the V1 contract only
+    /// paid for their set token balance.
+    fn storage_paid_by(&self, account_id: &AccountId) -> Balance {
+        self.storage_paid.get(account_id).unwrap_or_else(|| {
+            self.token.account_storage_usage as Balance * env::storage_byte_cost()
+        })
+    }
+
+    fn storage_balance(&self, account_id: &AccountId) -> StorageBalance {
+        StorageBalance { total: self.storage_paid_by(account_id).into(), available: 0.into() }
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    /// Register an account, charging for its set token balance, its mint record and one internal
+    /// balance per component. Any deposit above the minimum is refunded.
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<ValidAccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        // The storage balance is fixed, so a deposit is always registration only
+        let _ = registration_only;
+        let amount = env::attached_deposit();
+        let account_id: AccountId =
+            account_id.map(|id| id.into()).unwrap_or_else(env::predecessor_account_id);
+        if self.is_registered(&account_id) {
+            log!("The account is already registered, refunding the deposit");
+            if amount > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(amount);
+            }
+        } else {
+            let refund = amount - self.register_with_deposit(&account_id, amount);
+            if refund > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(refund);
+            }
+        }
+        self.storage_balance(&account_id)
+    }
+
+    /// Storage balances are fixed, so there is never anything available to withdraw
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        utils::assert_1_yocto();
+        let account_id = env::predecessor_account_id();
+        self.assert_registered(&account_id);
+        if amount.map(|amount| amount.0 > 0).unwrap_or(false) {
+            panic!("The amount is greater than the available storage balance");
+        }
+        self.storage_balance(&account_id)
+    }
+
+    /// Unregister the caller, refunding the deposit it paid. With `force`, the caller's set
+    /// tokens are handled according to the `AccountClosedPolicy` and its internal balances are
+    /// sent to it. A failed transfer of an internal balance leaves the tokens to the contract.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        utils::assert_1_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = match self.token.accounts.get(&account_id) {
+            Some(balance) => balance,
+            None => {
+                log!("The account {} is not registered", account_id);
+                return false;
+            }
+        };
+        let force = force.unwrap_or(false);
+        if balance > 0 && !force {
+            panic!("Can't unregister the account with the positive balance without force");
+        }
+        let internal_balances: Vec<(AccountId, Balance)> = self
+            .set_info
+            .token_ids()
+            .into_iter()
+            .map(|token_id| {
+                let internal_balance = self.balances.get_ft_balance(&account_id, &token_id);
+                (token_id, internal_balance)
+            })
+            .filter(|(_, internal_balance)| *internal_balance > 0)
+            .collect();
+        if !internal_balances.is_empty() && !force {
+            panic!("Can't unregister the account with positive internal balances without force");
+        }
+        for (token_id, internal_balance) in internal_balances {
+            self.withdraw_underlying(&account_id, &token_id, internal_balance);
+        }
+        self.checkpointed(&[account_id.clone()], |this| {
+            this.token.accounts.remove(&account_id);
+            this.token.total_supply -= balance;
+        });
+        let mut refund = self.storage_paid_by(&account_id);
+        self.storage_paid.remove(&account_id);
+        if self.set_info.minted_by(&account_id) > 0 {
+            // The mint record stays, so that registering again does not reset `max_account_mint`
+            let record_cost = MINTED_STORAGE_USAGE as Balance * env::storage_byte_cost();
+            refund = refund.saturating_sub(record_cost);
+        }
+        Promise::new(account_id.clone()).transfer(refund + 1);
+        self.on_account_closed(account_id, balance);
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        let min = self.storage_min_balance();
+        StorageBalanceBounds { min: min.into(), max: Some(min.into()) }
+    }
+
+    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+        if self.is_registered(account_id.as_ref()) {
+            Some(self.storage_balance(account_id.as_ref()))
+        } else {
+            None
+        }
+    }
+}
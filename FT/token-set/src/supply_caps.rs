@@ -0,0 +1,143 @@
+//! Optional caps on how many set tokens can be minted, as guard rails for young sets.
+//!
+//! `max_total_supply` bounds the total supply, fees included. `max_account_mint` bounds the set
+//! tokens an account gets by wrapping over its lifetime: what it minted is recorded and stays
+//! counted after the tokens are transferred away or unwrapped, while tokens received through
+//! transfers are not capped. Registration pays for the record, which is kept when the account
+//! unregisters so that registering again does not reset it.
+//!
+//! The caps change in two ways. The owner can only tighten them with `set_supply_caps`, adding a
+//! cap or lowering one, so a set can be reined in without a vote. Raising or lifting a cap takes
+//! a `SupplyCaps` proposal passed by the holders, which replaces both caps. The owners of hosted
+//! sets can only tighten their caps as well, with `mt_set_supply_caps`.
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{near_bindgen, Balance};
+
+use crate::utils;
+use crate::Contract;
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct SupplyCaps {
+    pub(crate) max_total_supply: Option<Balance>,
+    pub(crate) max_account_mint: Option<Balance>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SupplyCapsView {
+    pub max_total_supply: Option<U128>,
+    pub max_account_mint: Option<U128>,
+    /// How much can still be minted before reaching `max_total_supply`
+    pub total_supply_headroom: Option<U128>,
+}
+
+impl SupplyCaps {
+    /// Check that minting `amount` set tokens, of which `amount_to_caller` go to the caller who
+    /// `minted` set tokens before, stays within the caps
+    pub(crate) fn assert_can_mint(
+        &self,
+        ft: &FungibleToken,
+        minted: Balance,
+        amount: Balance,
+        amount_to_caller: Balance,
+    ) {
+        if let Some(max_total_supply) = self.max_total_supply {
+            if ft.total_supply + amount > max_total_supply {
+                panic!(
+                    "Minting {} would exceed the maximum total supply of {}",
+                    amount, max_total_supply
+                );
+            }
+        }
+        if let Some(headroom) = self.account_headroom(minted) {
+            if amount_to_caller > headroom {
+                panic!(
+                    "Minting {} would exceed the maximum of {} per account",
+                    amount_to_caller,
+                    self.max_account_mint.unwrap()
+                );
+            }
+        }
+    }
+
+    fn total_supply_headroom(&self, ft: &FungibleToken) -> Option<Balance> {
+        self.max_total_supply.map(|max| max.saturating_sub(ft.total_supply))
+    }
+
+    /// How much an account which `minted` set tokens can still mint
+    fn account_headroom(&self, minted: Balance) -> Option<Balance> {
+        self.max_account_mint.map(|max| max.saturating_sub(minted))
+    }
+
+    /// Whether every cap of `caps` is at most the current one
+    fn is_tightened_by(&self, caps: &SupplyCaps) -> bool {
+        is_tighter(self.max_total_supply, caps.max_total_supply)
+            && is_tighter(self.max_account_mint, caps.max_account_mint)
+    }
+
+    /// Replace the caps by `caps`, which may only add or lower caps
+    pub(crate) fn tighten(&mut self, caps: SupplyCaps) {
+        if !self.is_tightened_by(&caps) {
+            panic!("The owner can only add or lower caps, not raise or lift them");
+        }
+        *self = caps;
+    }
+}
+
+impl From<(Option<U128>, Option<U128>)> for SupplyCaps {
+    fn from((max_total_supply, max_account_mint): (Option<U128>, Option<U128>)) -> Self {
+        Self {
+            max_total_supply: max_total_supply.map(|max| max.0),
+            max_account_mint: max_account_mint.map(|max| max.0),
+        }
+    }
+}
+
+fn is_tighter(current: Option<Balance>, new: Option<Balance>) -> bool {
+    match (current, new) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(current), Some(new)) => new <= current,
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Add or lower caps, `None` meaning uncapped. Lowering a cap below the current supply or
+    /// balances only prevents further minting. Raising or lifting a cap goes through governance.
+    #[payable]
+    pub fn set_supply_caps(
+        &mut self,
+        max_total_supply: Option<U128>,
+        max_account_mint: Option<U128>,
+    ) {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        self.set_info.caps.tighten(SupplyCaps::from((max_total_supply, max_account_mint)));
+    }
+
+    pub fn get_supply_caps(&self) -> SupplyCapsView {
+        let caps = &self.set_info.caps;
+        SupplyCapsView {
+            max_total_supply: caps.max_total_supply.map(U128),
+            max_account_mint: caps.max_account_mint.map(U128),
+            total_supply_headroom: caps.total_supply_headroom(&self.token).map(U128),
+        }
+    }
+
+    /// How many more set tokens `account_id` can get by wrapping, `None` if uncapped. Set tokens
+    /// it minted before count against `max_account_mint` even once transferred or unwrapped.
+    pub fn get_mint_headroom(&self, account_id: ValidAccountId) -> Option<U128> {
+        let caps = &self.set_info.caps;
+        let account_headroom =
+            caps.account_headroom(self.set_info.minted_by(account_id.as_ref()));
+        match (caps.total_supply_headroom(&self.token), account_headroom) {
+            (Some(total), Some(account)) => Some(std::cmp::min(total, account)),
+            (total, account) => total.or(account),
+        }
+        .map(U128)
+    }
+}
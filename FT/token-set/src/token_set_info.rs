@@ -1,15 +1,46 @@
 use std::collections::HashSet;
 
 use near_contract_standards::fungible_token::FungibleToken;
-use near_internal_balance::ft::FungibleTokenBalances;
-use near_sdk::{collections::Vector, env, AccountId, Balance};
+use near_sdk::collections::{LookupMap, Vector};
+use near_sdk::{env, AccountId, Balance};
 
+use crate::internal_balances::InternalBalances;
+use crate::nft::NftBacking;
+use crate::rebalance::WEIGHT_DENOMINATOR;
+use crate::supply_caps::SupplyCaps;
 use crate::{FeeReceiver, SetInfo, TokenWithRatio, TokenWithRatioValid, utils::U256};
 
 const FEE_DENOMINATOR: u128 = 1_000_000_000_000_000;
+/// Scale of the backing donated per set token
+const DONATION_PRECISION: u128 = 1_000_000_000_000;
 
 impl SetInfo {
     pub(crate) fn new(set_ratios: Vec<TokenWithRatioValid>, set_initial_fee: FeeReceiver) -> Self {
+        Self::with_prefix(b"", set_ratios, set_initial_fee)
+    }
+
+    /// A set whose collections are stored under `prefix`, so that one contract can hold several
+    pub(crate) fn with_prefix(
+        prefix: &[u8],
+        set_ratios: Vec<TokenWithRatioValid>,
+        set_initial_fee: FeeReceiver,
+    ) -> Self {
+        let mut this = Self {
+            ratios: Vector::new([prefix, b"set-ratio"].concat()),
+            fee: set_initial_fee,
+            caps: SupplyCaps::default(),
+            reserves: LookupMap::new([prefix, b"rsv"].concat()),
+            nfts: NftBacking::new(prefix),
+            donated: LookupMap::new([prefix, b"don"].concat()),
+            minted: LookupMap::new([prefix, b"mnt"].concat()),
+        };
+        this.set_ratios(set_ratios);
+        assert_valid_fee(&this.fee);
+        this
+    }
+
+    /// Replace the tokens making up the set and their ratios
+    pub(crate) fn set_ratios(&mut self, set_ratios: Vec<TokenWithRatioValid>) {
         // TODO: check each token_id ratio unique....
         if set_ratios.len() == 0 {
             panic!("Expected at least one token in the set");
@@ -17,60 +48,198 @@ impl SetInfo {
 
         let mut token_ids: HashSet<AccountId> = HashSet::default();
 
-        let mut ratios = Vector::new(b"set-ratio".to_vec());
+        // Only replaced without supply, so nothing donated is left to holders
+        for ratio in self.ratios.iter() {
+            self.donated.remove(&ratio.token_id);
+        }
+        self.ratios.clear();
 
         for ratio in set_ratios {
+            if ratio.token_id.as_ref() == &env::current_account_id() {
+                panic!("A token set cannot contain itself");
+            }
             let not_present = token_ids.insert(ratio.token_id.clone().to_string());
             if !not_present {
                 panic!("Each token in the ratio must be unique");
             }
-            ratios.push(&TokenWithRatio { token_id: ratio.token_id.into(), ratio: ratio.ratio });
+            self.ratios.push(&TokenWithRatio {
+                token_id: ratio.token_id.into(),
+                ratio: ratio.ratio,
+                is_set: ratio.is_set,
+            });
         }
-        if set_initial_fee.owner_fee > FEE_DENOMINATOR
-            || set_initial_fee.platform_fee > FEE_DENOMINATOR
-        {
-            panic!("Expected the fees to be less than the fee denominator of {}", FEE_DENOMINATOR);
+    }
+
+    pub(crate) fn set_fee(&mut self, fee: FeeReceiver) {
+        assert_valid_fee(&fee);
+        self.fee = fee;
+    }
+
+    /// The amount of the component of `ratio` backing `amount` set tokens, donations included.
+    /// Rounded up for what is paid in and down for what is paid out.
+    pub(crate) fn backing_of(
+        &self,
+        ratio: &TokenWithRatio,
+        amount: Balance,
+        round_up: bool,
+    ) -> Balance {
+        let base = ratio.ratio as u128 * amount;
+        let donated = self.donated.get(&ratio.token_id).unwrap_or(0);
+        if donated == 0 {
+            return base;
         }
-        if set_initial_fee.owner_fee + set_initial_fee.platform_fee > FEE_DENOMINATOR {
-            panic!(
-                "Expected the sum of fees to be less than the fee denominator of {}",
-                FEE_DENOMINATOR
-            );
+        let scaled = U256::from(donated) * U256::from(amount);
+        let precision = U256::from(DONATION_PRECISION);
+        let mut extra = scaled / precision;
+        if round_up && !(scaled % precision).is_zero() {
+            extra = extra + U256::one();
         }
-        Self { ratios, fee: set_initial_fee }
+        base + extra.as_u128()
     }
 
-    pub(crate) fn on_burn(
+    /// Spread the backing of `amount` burned set tokens over the `backed_supply` left, so that
+    /// every remaining set token is backed by more of each component.
+    ///
+    /// return whether the donation could be represented, nothing changes otherwise
+    pub(crate) fn donate(&mut self, amount: Balance, backed_supply: Balance) -> bool {
+        let mut donated = vec![];
+        for ratio in self.ratios.iter() {
+            let backing = self.backing_of(&ratio, amount, false);
+            let per_token =
+                U256::from(backing) * U256::from(DONATION_PRECISION) / U256::from(backed_supply);
+            let total = per_token + U256::from(self.donated.get(&ratio.token_id).unwrap_or(0));
+            if total > U256::from(u128::MAX) {
+                return false;
+            }
+            donated.push((ratio.token_id, total.as_u128()));
+        }
+        for (token_id, total) in donated {
+            self.donated.insert(&token_id, &total);
+        }
+        true
+    }
+
+    /// The backing of each component donated per set token, scaled by `DONATION_PRECISION`
+    pub(crate) fn donated_backing(&self) -> Vec<Balance> {
+        self.ratios.iter().map(|r| self.donated.get(&r.token_id).unwrap_or(0)).collect()
+    }
+
+    pub(crate) fn token_ids(&self) -> Vec<AccountId> {
+        self.ratios.iter().map(|r| r.token_id).collect()
+    }
+
+    pub(crate) fn get_ratios(&self) -> Vec<TokenWithRatio> {
+        self.ratios.to_vec()
+    }
+
+    /// Replace the ratio of every token, keeping the tokens in place
+    pub(crate) fn update_ratios(&mut self, new_ratios: &[u32]) {
+        assert_eq!(new_ratios.len() as u64, self.ratios.len(), "Expected a ratio for each token");
+        for (i, ratio) in new_ratios.iter().enumerate() {
+            let token = self.ratios.get(i as u64).unwrap();
+            self.ratios.replace(i as u64, &TokenWithRatio { ratio: *ratio, ..token });
+        }
+    }
+
+    /// Burn `amount` set tokens of `account_id`, or all of them if `None`.
+    ///
+    /// return the amount burned
+    pub(crate) fn burn(
         &self,
-        balances: &mut FungibleTokenBalances,
+        ft: &mut FungibleToken,
+        account_id: &AccountId,
+        amount: Option<Balance>,
+    ) -> Balance {
+        let amount = amount.unwrap_or_else(|| ft.internal_unwrap_balance_of(account_id));
+        if amount == 0 {
+            panic!("Expected a positive amount to unwrap");
+        }
+        ft.internal_withdraw(account_id, amount);
+        amount
+    }
+
+    /// Like `on_burn` but the components which are token sets themselves are not credited.
+    ///
+    /// return the amounts of the token set components
+    pub(crate) fn on_burn_nested(
+        &mut self,
+        balances: &mut InternalBalances,
+        account_id: &AccountId,
+        amount: Balance,
+    ) -> Vec<(AccountId, Balance)> {
+        let mut nested = vec![];
+        for i in 0..self.ratios.len() {
+            let ratio = &self.ratios.get(i).unwrap();
+            let backing = self.backing_of(ratio, amount, false);
+            self.remove_reserve(&ratio.token_id, backing);
+            if ratio.is_set {
+                nested.push((ratio.token_id.clone(), backing));
+            } else {
+                balances.increase_balance(account_id, &ratio.token_id, backing);
+            }
+        }
+        nested
+    }
+
+    pub(crate) fn on_burn(
+        &mut self,
+        balances: &mut InternalBalances,
         account_id: AccountId,
         amount: Balance,
     ) {
         for i in 0..self.ratios.len() {
             let ratio = &self.ratios.get(i).unwrap();
-            balances.increase_balance(&account_id, &ratio.token_id, ratio.ratio as u128 * amount);
+            let backing = self.backing_of(ratio, amount, false);
+            self.remove_reserve(&ratio.token_id, backing);
+            balances.increase_balance(&account_id, &ratio.token_id, backing);
         }
     }
 
+    /// The amount of `token_id` held as backing of the set tokens
+    pub(crate) fn reserve_of(&self, token_id: &AccountId) -> Balance {
+        self.reserves.get(token_id).unwrap_or(0)
+    }
+
+    pub(crate) fn add_reserve(&mut self, token_id: &AccountId, amount: Balance) {
+        self.reserves.insert(token_id, &(self.reserve_of(token_id) + amount));
+    }
+
+    pub(crate) fn remove_reserve(&mut self, token_id: &AccountId, amount: Balance) {
+        let reserve = self.reserve_of(token_id);
+        if reserve < amount {
+            panic!("The reserve of {} is {}, cannot remove {}", token_id, reserve, amount);
+        }
+        self.reserves.insert(token_id, &(reserve - amount));
+    }
+
+    /// Lower the owner fee. Only the holders of the contract's own set can raise it, through a
+    /// `Fees` proposal.
     pub(crate) fn change_owner_fee(&mut self, new_fee: u128) {
-        let caller = env::predecessor_account_id();
-        self.fee.owner_fee = new_fee;
+        if new_fee > self.fee.owner_fee {
+            panic!("The owner can only lower the owner fee, not raise it");
+        }
+        self.set_fee(FeeReceiver {
+            owner_fee: new_fee,
+            platform_fee: self.fee.platform_fee,
+            platform_id: self.fee.platform_id.clone(),
+        });
     }
 
     /// Decrease the balances of the underlying tokens and wrap the tokens.
-    /// Also, send the apportioned fee amount
+    /// Also, send the apportioned fee amount, less the caller's discount. The referral share of
+    /// the owner fee is left unminted for the referrer.
     ///
-    /// return the amount wrapped and given to the wrapper
+    /// return the amount wrapped and given to the wrapper, and the referral fee
     pub(crate) fn wrap(
-        &self,
+        &mut self,
         owner: &AccountId,
+        caller: &AccountId,
         ft: &mut FungibleToken,
-        balances: &mut FungibleTokenBalances,
+        balances: &mut InternalBalances,
         amount: Option<Balance>,
-    ) -> Balance {
-        // TODO: hmmmmm... should this be the predecessor or the signer???
-        let caller = env::predecessor_account_id();
-        let max_amount_wrapped = self.get_max_amount(balances, &caller);
+        adjustments: FeeAdjustments,
+    ) -> (Balance, Balance) {
+        let max_amount_wrapped = self.get_max_amount(balances, caller);
         let amount_wrap = amount.unwrap_or(max_amount_wrapped);
         // TODO: add test for this
         if amount_wrap > max_amount_wrapped {
@@ -85,42 +254,76 @@ impl SetInfo {
         let platform_incr = (U256::from(amount_wrap) * U256::from(self.fee.platform_fee)
             / U256::from(FEE_DENOMINATOR))
         .as_u128();
+        let owner_inrcr = apply_bps(owner_inrcr, WEIGHT_DENOMINATOR - adjustments.discount_bps);
+        let platform_incr =
+            apply_bps(platform_incr, WEIGHT_DENOMINATOR - adjustments.discount_bps);
+        let referral_incr = apply_bps(owner_inrcr, adjustments.referral_bps);
 
         let amount_wrap_caller = amount_wrap - owner_inrcr - platform_incr;
+        let minted = self.minted_by(caller);
+        self.caps.assert_can_mint(ft, minted, amount_wrap, amount_wrap_caller);
+        if amount_wrap_caller > 0 {
+            self.minted.insert(caller, &(minted + amount_wrap_caller));
+        }
 
         // Do the internal deposits
-        ft.internal_deposit(&caller, amount_wrap_caller);
-        ft.internal_deposit(&owner, owner_inrcr);
+        ft.internal_deposit(caller, amount_wrap_caller);
+        ft.internal_deposit(&owner, owner_inrcr - referral_incr);
         ft.internal_deposit(&self.fee.platform_id, platform_incr);
 
-        self.decrease_potentials(balances, amount_wrap, &caller);
+        self.decrease_potentials(balances, amount_wrap, caller);
+
+        (amount_wrap, referral_incr)
+    }
 
-        amount_wrap
+    /// The set tokens `account_id` got by wrapping so far
+    pub(crate) fn minted_by(&self, account_id: &AccountId) -> Balance {
+        self.minted.get(account_id).unwrap_or(0)
     }
 
     fn decrease_potentials(
-        &self,
-        balances: &mut FungibleTokenBalances,
+        &mut self,
+        balances: &mut InternalBalances,
         amount_out: Balance,
         account_id: &AccountId,
     ) {
         for i in 0..self.ratios.len() {
             let ratio = &self.ratios.get(i).unwrap();
-            balances.subtract_balance(
-                &account_id,
-                &ratio.token_id,
-                (ratio.ratio as u128) * amount_out,
-            )
+            let backing = self.backing_of(ratio, amount_out, true);
+            balances.subtract_balance(&account_id, &ratio.token_id, backing);
+            self.add_reserve(&ratio.token_id, backing);
         }
     }
 
-    fn get_max_amount(&self, balances: &FungibleTokenBalances, account_id: &AccountId) -> Balance {
+    fn get_max_amount(&self, balances: &InternalBalances, account_id: &AccountId) -> Balance {
+        self.max_amount_except(balances, account_id, None)
+    }
+
+    /// The most `account_id` can wrap with its internal balances of the components other than
+    /// `except`, `u128::MAX` if there are none
+    pub(crate) fn max_amount_except(
+        &self,
+        balances: &InternalBalances,
+        account_id: &AccountId,
+        except: Option<&AccountId>,
+    ) -> Balance {
         let mut min = u128::MAX;
         for i in 0..self.ratios.len() {
             let ratio = &self.ratios.get(i).unwrap();
+            if Some(&ratio.token_id) == except {
+                continue;
+            }
             let bal = balances.get_ft_balance(&account_id, &ratio.token_id);
 
-            let amount_out = bal / (ratio.ratio as u128);
+            let donated = self.donated.get(&ratio.token_id).unwrap_or(0);
+            let per_token = U256::from(ratio.ratio) * U256::from(DONATION_PRECISION)
+                + U256::from(donated);
+            let mut amount_out =
+                (U256::from(bal) * U256::from(DONATION_PRECISION) / per_token).as_u128();
+            // The donated backing is rounded up when paid in
+            if amount_out > 0 && self.backing_of(ratio, amount_out, true) > bal {
+                amount_out -= 1;
+            }
             if amount_out < min {
                 min = amount_out;
             }
@@ -128,3 +331,28 @@ impl SetInfo {
         min
     }
 }
+
+/// Adjustments of the fees of a single wrap, in basis points
+#[derive(Default)]
+pub(crate) struct FeeAdjustments {
+    /// The share of the owner fee going to the referrer
+    pub(crate) referral_bps: u32,
+    /// The discount on the owner and platform fees
+    pub(crate) discount_bps: u32,
+}
+
+fn apply_bps(amount: Balance, bps: u32) -> Balance {
+    (U256::from(amount) * U256::from(bps) / U256::from(WEIGHT_DENOMINATOR)).as_u128()
+}
+
+fn assert_valid_fee(fee: &FeeReceiver) {
+    if fee.owner_fee > FEE_DENOMINATOR || fee.platform_fee > FEE_DENOMINATOR {
+        panic!("Expected the fees to be less than the fee denominator of {}", FEE_DENOMINATOR);
+    }
+    if fee.owner_fee + fee.platform_fee > FEE_DENOMINATOR {
+        panic!(
+            "Expected the sum of fees to be less than the fee denominator of {}",
+            FEE_DENOMINATOR
+        );
+    }
+}
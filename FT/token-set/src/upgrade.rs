@@ -0,0 +1,175 @@
+//! Contract upgrades and the migration of state written by previous versions.
+//!
+//! The version of the state layout is stored under its own key, next to the contract state. State
+//! written before versioning was introduced has no version and is `StateVersion::V1`.
+//!
+//! `StateVersion::V2` is the layout of the first release with versioning, which is only deployed
+//! once all the features it carries are in: until then `Contract` still changes and the V1
+//! conversion is kept up to date with it. Any layout change after that release needs a new
+//! version, with a conversion from V2.
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::FungibleToken;
+use near_internal_balance::ft::FungibleTokenBalances;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LazyOption, LookupMap, LookupSet, Vector};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Balance, Gas, Promise};
+
+use crate::access::AccessControl;
+use crate::allowlist::{ComponentVerification, ComponentsStatus};
+use crate::checkpoints::Checkpoints;
+use crate::fee_tiers::FeeTiers;
+use crate::governance::Governance;
+use crate::harvest::Harvester;
+use crate::icon::SetIcon;
+use crate::intents::IntentSigners;
+use crate::internal_balances::InternalBalances;
+use crate::multi_token::MultiTokenHost;
+use crate::nft::NftBacking;
+use crate::rebalance::Rebalancer;
+use crate::referrals::Referrals;
+use crate::supply_caps::SupplyCaps;
+use crate::utils;
+use crate::{AccountClosedPolicy, Contract, FeeReceiver, SetInfo, TokenWithRatio};
+
+const STATE_VERSION_KEY: &[u8] = b"version";
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_MIGRATE: Gas = 100_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum StateVersion {
+    /// The initial release, see `ContractV1`
+    V1,
+    /// The first release with versioned state, the layout of `Contract`
+    V2,
+}
+
+pub const CURRENT_STATE_VERSION: StateVersion = StateVersion::V2;
+
+pub(crate) fn read_state_version() -> StateVersion {
+    env::storage_read(STATE_VERSION_KEY)
+        .map(|version| {
+            StateVersion::try_from_slice(&version).expect("Failed to read the state version")
+        })
+        .unwrap_or(StateVersion::V1)
+}
+
+pub(crate) fn write_state_version(version: StateVersion) {
+    env::storage_write(STATE_VERSION_KEY, &version.try_to_vec().unwrap());
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct TokenWithRatioV1 {
+    token_id: AccountId,
+    ratio: u32,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct SetInfoV1 {
+    ratios: Vector<TokenWithRatioV1>,
+    fee: FeeReceiver,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct ContractV1 {
+    owner_id: AccountId,
+    token: FungibleToken,
+    metadata: LazyOption<FungibleTokenMetadata>,
+    balances: FungibleTokenBalances,
+    set_info: SetInfoV1,
+}
+
+impl From<ContractV1> for Contract {
+    fn from(old: ContractV1) -> Self {
+        // The ratios are stored one by one, so they are rewritten in place with the new layout
+        let old_ratios: Vec<TokenWithRatioV1> = old.set_info.ratios.to_vec();
+        let mut ratios = Vector::new(b"set-ratio".to_vec());
+        // V1 held exactly the backing of its set tokens
+        let mut reserves = LookupMap::new(b"rsv".to_vec());
+        for old_ratio in old_ratios {
+            let reserve = old.token.total_supply * old_ratio.ratio as u128;
+            reserves.insert(&old_ratio.token_id, &reserve);
+            ratios.push(&TokenWithRatio {
+                token_id: old_ratio.token_id,
+                ratio: old_ratio.ratio,
+                is_set: false,
+            });
+        }
+        Self {
+            owner_id: old.owner_id,
+            token: old.token,
+            metadata: old.metadata,
+            balances: InternalBalances::with_balances(old.balances),
+            set_info: SetInfo {
+                ratios,
+                fee: old.set_info.fee,
+                caps: SupplyCaps::default(),
+                reserves,
+                nfts: NftBacking::new(b""),
+                donated: LookupMap::new(b"don".to_vec()),
+                // What accounts wrapped with the V1 contract is not known
+                minted: LookupMap::new(b"mnt".to_vec()),
+            },
+            rebalancer: Rebalancer::new(),
+            // V1 credited the underlying tokens of closed accounts to the platform
+            account_closed_policy: AccountClosedPolicy::Platform,
+            escrowed: LookupMap::new(b"esc".to_vec()),
+            total_escrowed: 0,
+            roles: LookupSet::new(b"roles".to_vec()),
+            icon: SetIcon { auto: old.metadata.get().unwrap().icon.is_none(), symbols: vec![] },
+            // Sets deployed before the allowlist are already in use
+            verification: ComponentVerification::new(None, ComponentsStatus::Verified),
+            access: AccessControl::new(),
+            checkpoints: Checkpoints::new(),
+            governance: Governance::new(),
+            harvester: Harvester::new(),
+            wnear_id: None,
+            multi_token: MultiTokenHost::new(),
+            allowances: LookupMap::new(b"alw".to_vec()),
+            intent_signers: IntentSigners::new(),
+            referrals: Referrals::new(),
+            fee_tiers: FeeTiers::new(),
+            storage_paid: LookupMap::new(b"stp".to_vec()),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Deploy new code to the contract and migrate the state to it. The code is passed as raw,
+    /// Borsh serialized bytes.
+    #[payable]
+    pub fn upgrade(&mut self, #[serializer(borsh)] code: Vec<u8>) -> Promise {
+        utils::assert_1_yocto();
+        self.assert_owner();
+        Promise::new(env::current_account_id()).deploy_contract(code).function_call(
+            b"migrate".to_vec(),
+            vec![],
+            NO_DEPOSIT,
+            GAS_FOR_MIGRATE,
+        )
+    }
+
+    /// Convert the state written by any previous version to the current layout
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Only the contract itself can migrate its state"
+        );
+        let this: Contract = match read_state_version() {
+            StateVersion::V1 => env::state_read::<ContractV1>()
+                .expect("Failed to read the V1 state")
+                .into(),
+            StateVersion::V2 => env::state_read().expect("Failed to read the state"),
+        };
+        write_state_version(CURRENT_STATE_VERSION);
+        this
+    }
+
+    pub fn get_state_version(&self) -> StateVersion {
+        read_state_version()
+    }
+}
@@ -1,4 +1,4 @@
-use near_sdk::env;
+use near_sdk::{env, Balance, Promise, StorageUsage};
 use uint::construct_uint;
 
 use crate::Contract;
@@ -8,6 +8,40 @@ pub(crate) fn assert_1_yocto() {
     assert_eq!(env::attached_deposit(), 1, "Expected an attached deposit of 1");
 }
 
+/// Combine promises with `and`, panics if there are none
+pub(crate) fn join_promises<I: Iterator<Item = Promise>>(mut promises: I) -> Promise {
+    let first = promises.next().expect("Expected at least one promise");
+    promises.fold(first, |joined, promise| joined.and(promise))
+}
+
+pub(crate) fn assert_at_least_1_yocto() {
+    assert!(env::attached_deposit() >= 1, "Expected an attached deposit of at least 1");
+}
+
+/// Send back the unused part of the attached deposit, keeping the yoctoNEAR which confirms the
+/// call. `unused` includes that yoctoNEAR.
+pub(crate) fn refund_deposit(unused: Balance) {
+    if unused > 1 {
+        Promise::new(env::predecessor_account_id()).transfer(unused - 1);
+    }
+}
+
+/// Pay for the storage used since `initial_usage` from the attached deposit, refunding the rest
+pub(crate) fn charge_storage(initial_usage: StorageUsage) {
+    charge_storage_with_reserve(initial_usage, 0);
+}
+
+/// Like `charge_storage`, also paying for `reserved` bytes which later calls will use
+pub(crate) fn charge_storage_with_reserve(initial_usage: StorageUsage, reserved: StorageUsage) {
+    let cost = (env::storage_usage().saturating_sub(initial_usage) + reserved) as Balance
+        * env::storage_byte_cost();
+    let attached = env::attached_deposit();
+    if attached < cost {
+        panic!("{} yoctoNEAR have to be attached to cover the storage, got {}", cost, attached);
+    }
+    refund_deposit(attached - cost);
+}
+
 construct_uint! {
     /// 256-bit unsigned integer.
     pub struct U256(4);